@@ -0,0 +1,72 @@
+//!
+//! Bench - Measure the tokenising throughput wrt long comma separated lines
+//! HanishKVC, 2022
+//!
+//! cargo run --release --features bench --example bench [fields_per_line] [lines] [rounds]
+//!
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokensk::{TStrX, TConfig};
+
+
+/// Create a line with the given number of comma separated fields, containing a mix
+/// of normal, string and bracketed tokens, with some escape sequences and spaces.
+fn make_line(nfields: usize, seed: usize) -> String {
+    let mut vfields = Vec::with_capacity(nfields);
+    for i in 0..nfields {
+        let n = i + seed;
+        let field = match n % 6 {
+            0 => format!("field{}", n),
+            1 => format!("  {}.{}  ", n, n % 97),
+            2 => format!(r#""a string, with \"quotes\" {}""#, n),
+            3 => format!("fn{}(a, (b, c), d)", n),
+            4 => format!(r"some text\twith escape {}", n),
+            _ => format!("ॐ unicode ñ {}", n),
+        };
+        vfields.push(field);
+    }
+    vfields.join(",")
+}
+
+/// Tokenise the given lines using the given config, and return the best time across
+/// the given number of rounds, along with the number of tokens.
+fn run(cfg: &Arc<TConfig>, vlines: &[String], nrounds: usize) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut ntoks = 0;
+    for _ in 0..nrounds {
+        let start = Instant::now();
+        ntoks = 0;
+        for line in vlines {
+            let mut tstr = cfg.from_str(line, true);
+            let toks = tstr.tokens_vec_ex(',', true, false).unwrap();
+            ntoks += toks.len();
+        }
+        best = best.min(start.elapsed());
+    }
+    (best, ntoks)
+}
+
+fn main() {
+    let args: Vec<usize> = std::env::args().skip(1).map(|arg| arg.parse().expect("bench: args should be numbers")).collect();
+    let nfields = args.first().copied().unwrap_or(1000);
+    let nlines = args.get(1).copied().unwrap_or(200);
+    let nrounds = args.get(2).copied().unwrap_or(5);
+
+    let vlines: Vec<String> = (0..nlines).map(|i| make_line(nfields, i)).collect();
+    let nbytes: usize = vlines.iter().map(|line| line.len()).sum();
+    let cfg = TStrX::new().config();
+    println!("bench: {} lines x {} fields, {} bytes", nlines, nfields, nbytes);
+
+    // The baseline, where every char goes through the full chain of chartypes
+    let mut vsecs = Vec::new();
+    for (name, cfg) in [("baseline (uncompiled chain)", cfg.uncompiled()), ("compiled char classes", cfg)] {
+        let (best, ntoks) = run(&cfg, &vlines, nrounds);
+        assert_eq!(ntoks, nfields * nlines);
+        let secs = best.as_secs_f64();
+        println!("bench: {}: best of {} rounds: {:.3} s, {:.2} MB/s, {:.0} tokens/s", name, nrounds, secs, nbytes as f64 / secs / 1e6, ntoks as f64 / secs);
+        vsecs.push(secs);
+    }
+    println!("bench: speedup wrt baseline: {:.2}x", vsecs[0] / vsecs[1]);
+}
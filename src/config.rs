@@ -0,0 +1,100 @@
+//!
+//! Config - The tokenisation characteristics, compiled once and shared across TStr's
+//! HanishKVC, 2022
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use crate::{TStr, TStrX, Delimiters, Flags};
+use crate::nexttoken::VCharTypes;
+
+
+///
+/// The tokenisation characteristics (delims, escseqs, flags), along with the
+/// chartypes chain compiled from them.
+///
+/// It is immutable, and is shared (through a Arc) across the TStr's (and the
+/// TString's and TReader's) using it, instead of each of them having to copy
+/// it, or having to compile the chain wrt each token. It is Send and Sync, so
+/// it can be shared across threads.
+///
+/// Create it using TStrX::config, which inturn allows the characteristics to
+/// be setup as required.
+///
+pub struct TConfig {
+    pub(crate) delims: Delimiters,
+    pub(crate) escseqs: HashMap<char, char>,
+    pub(crate) flags: Flags,
+    pub(crate) vchartypes: VCharTypes,
+}
+
+impl TConfig {
+
+    /// Create the config, with the given characteristics.
+    pub fn new(delims: Delimiters, escseqs: HashMap<char, char>, flags: Flags) -> TConfig {
+        let vchartypes = VCharTypes::from_delimiters(&delims);
+        TConfig {
+            delims,
+            escseqs,
+            flags,
+            vchartypes,
+        }
+    }
+
+    /// The config with the default characteristics, which is created once and shared.
+    pub fn shared_default() -> Arc<TConfig> {
+        static DEFAULT: OnceLock<Arc<TConfig>> = OnceLock::new();
+        DEFAULT.get_or_init(|| Arc::new(TConfig::new(Delimiters::default(), TStrX::escseqs_default(), Flags::default()))).clone()
+    }
+
+    /// Delimiters used to demarcate the tokens
+    pub fn delims(&self) -> &Delimiters {
+        &self.delims
+    }
+
+    /// The set of supported escape sequences and the underlying expanded char.
+    pub fn escseqs(&self) -> &HashMap<char, char> {
+        &self.escseqs
+    }
+
+    /// The tokenisation characteristics
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    ///
+    /// A copy of this config, where every char is processed through the full chain of
+    /// chartypes, instead of only those which can act on it. Used as a baseline wrt the
+    /// throughput, by examples/bench.rs.
+    ///
+    #[cfg(feature = "bench")]
+    pub fn uncompiled(&self) -> Arc<TConfig> {
+        Arc::new(TConfig {
+            delims: self.delims.clone(),
+            escseqs: self.escseqs.clone(),
+            flags: self.flags.clone(),
+            vchartypes: VCharTypes::from_delimiters_uncompiled(&self.delims),
+        })
+    }
+
+    /// Create a TStr, which uses (shares) this config, for the given string slice.
+    ///
+    /// If btrim is set, then trim the string
+    pub fn from_str<'a>(self: &Arc<Self>, s: &'a str, btrim: bool) -> TStr<'a> {
+        TStr::from_str_cfg(s, btrim, self.clone())
+    }
+
+}
+
+/// The compiled chartypes chain is left out, as it is derived from the delims.
+impl fmt::Debug for TConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TConfig")
+            .field("delims", &self.delims)
+            .field("escseqs", &self.escseqs)
+            .field("flags", &self.flags)
+            .finish_non_exhaustive()
+    }
+}
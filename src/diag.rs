@@ -0,0 +1,138 @@
+//!
+//! Diag - Render tokenisation errors as human readable diagnostics
+//! HanishKVC, 2022
+//!
+
+use std::fmt::Write;
+
+use crate::{Error, ErrorKind, LineMap};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The output mode of the diagnostic report
+pub enum Style {
+    /// Plain text
+    Plain,
+    /// Text with ANSI colour escape codes, for showing on a terminal
+    Ansi,
+}
+
+const ANSI_RESET: &str = "\x1b[0m";
+const ANSI_ERROR: &str = "\x1b[1;31m";
+const ANSI_GUTTER: &str = "\x1b[1;34m";
+const ANSI_NOTE: &str = "\x1b[1;36m";
+
+
+/// A plain language explanation of the given kind of error, including what could be done about it
+pub fn explain(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::UnknownEscSeq => "the char after the escape char is not a known escape sequence; escape the escape char itself if it is meant literally",
+        ErrorKind::UnbalancedClosingBracket => "this closing bracket has no opening bracket before it; remove it, escape it or add the missing opening bracket",
+        ErrorKind::UnterminatedString => "the string opened here is not closed by the end of the text; add the closing quote, or escape the quote if it is meant literally",
+        ErrorKind::UnterminatedBracket => "the bracket opened here is not closed by the end of the text; add the closing bracket, or escape the bracket if it is meant literally",
+        ErrorKind::JunkAfterBlockToken => "a string or bracketed block must be followed by a delimiter (or the end of the text), but other chars follow it here",
+        ErrorKind::BracketNotAllowedAtStart => "a token cant start with a opening bracket with the current settings; escape the bracket or allow standalone bracketed blocks",
+        ErrorKind::BracketNotAllowedInMiddle => "a opening bracket cant follow other chars in a token with the current settings; escape the bracket or allow prefixed bracketed blocks",
+        ErrorKind::MismatchedBracket => "this closing bracket is of a different type than the bracket opened last; close the brackets in the reverse order in which they are opened",
+        ErrorKind::UnterminatedComment => "the block comment opened here is not closed by the end of the text; add the end marker of the comment",
+        ErrorKind::TokenTooLong => "the token is larger than the maximum buffer size allowed; check for a unclosed string or bracket, or increase the buffer size",
+        ErrorKind::MalformedEscSeq => "the numeric escape sequence has missing or extra digits or braces",
+        ErrorKind::InvalidCodePoint => "the numeric escape sequence doesnt correspond to a valid unicode char",
+        ErrorKind::Unrepresentable => "the value cant be written as a token, which tokenises back to the same value, with the current settings",
+        ErrorKind::BracketNotFound => "a bracketed block (of the required type) was expected, but not found",
+        ErrorKind::StringNotFound => "a quoted string (of the required type) was expected, but not found",
+        ErrorKind::InvalidValue => "the token is not a valid value of the required type",
+        ErrorKind::InvalidNumber => "the token is not a valid number",
+        ErrorKind::NumberOutOfRange => "the token is a valid number, but it doesnt fit in the required type",
+    }
+}
+
+/// Return the byte offset, within the given text, nearest to the given offset, which is a char boundry
+fn char_boundry(text: &str, mut pos: usize) -> usize {
+    pos = pos.min(text.len());
+    while !text.is_char_boundary(pos) {
+        pos -= 1;
+    }
+    pos
+}
+
+/// Mark the cols corresponding to the given byte range (clamped to the line) using the given char
+fn mark(marks: &mut [char], line: &str, lstart: usize, range: (usize, usize), mch: char) {
+    let (start, end) = range;
+    let lend = lstart + line.len();
+    if (end < lstart) || (start > lend) {
+        return;
+    }
+    let start = char_boundry(line, start.max(lstart) - lstart);
+    let end = char_boundry(line, end.min(lend) - lstart);
+    let icol = line[..start].chars().count();
+    let ncols = line[start..end].chars().count().max(1);
+    for m in marks.iter_mut().skip(icol).take(ncols) {
+        *m = mch;
+    }
+}
+
+///
+/// Render the given error, wrt the original text given to the tokeniser, into a
+/// multi line report. The report contains the line (in the text) with the error,
+/// a caret (^) under the offending char, with the token involved (if known)
+/// underlined using ~ and the char which opened the block involved (if any and
+/// if in the same line) marked using -, followed by a plain language explanation.
+///
+/// The positions in the error, are expected to be wrt the given text, as is the
+/// case with the errors returned by the tokenising methods of TStr.
+///
+/// NOTE: Each char is assumed to occupy a single column, except for tabs, which
+/// are retained as is in the underline, so that it aligns with the line above.
+///
+pub fn render(text: &str, err: &Error, style: Style) -> String {
+    let (cerr, cgut, cnote, creset) = match style {
+        Style::Plain => ("", "", "", ""),
+        Style::Ansi => (ANSI_ERROR, ANSI_GUTTER, ANSI_NOTE, ANSI_RESET),
+    };
+    let lmap = LineMap::new(text);
+    let chpos = char_boundry(text, err.chpos);
+    let loc = lmap.location(chpos);
+    let (lineno, col) = (loc.line, loc.col_char);
+    let line = lmap.line(lineno).unwrap_or("");
+    let lstart = chpos + 1 - loc.col_byte;
+    let lend = lstart + line.len();
+
+    let mut marks: Vec<char> = vec![' '; line.chars().count() + 1];
+    if let Some(span) = &err.span {
+        mark(&mut marks, line, lstart, (span.start, span.end), '~');
+    }
+    if let Some(blockpos) = err.blockpos {
+        if blockpos != chpos {
+            mark(&mut marks, line, lstart, (blockpos, blockpos), '-');
+        }
+    }
+    mark(&mut marks, line, lstart, (chpos, chpos), '^');
+    let mut underline = String::new();
+    for (m, lch) in marks.iter().zip(line.chars().chain(std::iter::once(' '))) {
+        if (*m == ' ') && (lch == '\t') {
+            underline.push('\t');
+        } else {
+            underline.push(*m);
+        }
+    }
+    let underline = underline.trim_end();
+
+    let gutter = " ".repeat(lineno.to_string().len());
+    let mut out = String::new();
+    let _ = writeln!(out, "{}error{}: {} [{}]", cerr, creset, err.kind, err.ch.escape_debug());
+    let _ = writeln!(out, "{}{}-->{} line {}, col {}", gutter, cgut, creset, lineno, col);
+    let _ = writeln!(out, "{} {}|{}", gutter, cgut, creset);
+    let _ = writeln!(out, "{}{} |{} {}", cgut, lineno, creset, line);
+    let _ = writeln!(out, "{} {}|{} {}{}{}", gutter, cgut, creset, cerr, underline, creset);
+    if let Some(blockpos) = err.blockpos.filter(|blockpos| *blockpos != chpos) {
+        if (blockpos >= lstart) && (blockpos <= lend) {
+            let _ = writeln!(out, "{} {}={} note: - marks where the block was opened", gutter, cnote, creset);
+        } else {
+            let bloc = lmap.location(blockpos);
+            let _ = writeln!(out, "{} {}={} note: the block was opened at line {}, col {}", gutter, cnote, creset, bloc.line, bloc.col_char);
+        }
+    }
+    let _ = writeln!(out, "{} {}={} help: {}", gutter, cnote, creset, explain(err.kind));
+    out
+}
@@ -0,0 +1,149 @@
+//!
+//! Error - The errors reported by the tokenisation logic
+//! HanishKVC, 2022
+//!
+
+use std::fmt;
+use std::ops::Range;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+/// The kind of problem identified when scanning for a token.
+///
+/// These are stable, so that users can match on them, instead of
+/// having to look into the text of the error message.
+pub enum ErrorKind {
+    /// A escape sequence, which is not part of the escape sequence map
+    UnknownEscSeq,
+    /// A closing bracket, which doesnt have a corresponding opening bracket
+    UnbalancedClosingBracket,
+    /// A string, which is not closed by the time the line ended
+    UnterminatedString,
+    /// A bracketed block, which is not closed by the time the line ended
+    UnterminatedBracket,
+    /// Non delimiter char found after the end of a string/bracket block token,
+    /// when blocktok_dlimuser_endreqd is set.
+    JunkAfterBlockToken,
+    /// Opening bracket at the begining of a token, when mainbracket_beginstandalone is not set
+    BracketNotAllowedAtStart,
+    /// Opening bracket in the middle of a token, when mainbracket_beginprefixed is not set
+    BracketNotAllowedInMiddle,
+    /// A closing bracket, which doesnt match the last opened bracket, when bracket_strict is set
+    MismatchedBracket,
+    /// A block comment, which is not closed by the time the line ended
+    UnterminatedComment,
+    /// A token, which needs more buffer than allowed, when reading from a BufRead
+    TokenTooLong,
+    /// A numeric (hex/octal/unicode) escape sequence, with missing/extra digits or braces
+    MalformedEscSeq,
+    /// A numeric escape sequence, whose value is not a valid unicode scalar value
+    InvalidCodePoint,
+    /// A value, which cant be quoted/joined so as to tokenise back to itself,
+    /// with the current tokenisation characteristics
+    Unrepresentable,
+    /// No configured (or the specified) bracket type found, when peeling a bracketed block
+    BracketNotFound,
+    /// No configured (or the specified) string quote found, when peeling a string
+    StringNotFound,
+    /// A token, which is not a valid value of the requested type
+    InvalidValue,
+    /// A token, which is not a valid number
+    InvalidNumber,
+    /// A token, which is a valid number, but outside the range of the requested type
+    NumberOutOfRange,
+}
+
+impl ErrorKind {
+
+    /// A short description of the kind of error
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::UnknownEscSeq => "unknown escape sequence",
+            ErrorKind::UnbalancedClosingBracket => "closing bracket without matching opening bracket",
+            ErrorKind::UnterminatedString => "unterminated string",
+            ErrorKind::UnterminatedBracket => "unterminated bracket",
+            ErrorKind::JunkAfterBlockToken => "non delimiter char after block token",
+            ErrorKind::BracketNotAllowedAtStart => "opening bracket not allowed at start of token",
+            ErrorKind::BracketNotAllowedInMiddle => "opening bracket not allowed in middle of token",
+            ErrorKind::MismatchedBracket => "closing bracket doesnt match the last opened bracket",
+            ErrorKind::UnterminatedComment => "unterminated block comment",
+            ErrorKind::TokenTooLong => "token exceeds the maximum buffer size",
+            ErrorKind::MalformedEscSeq => "malformed numeric escape sequence",
+            ErrorKind::InvalidCodePoint => "escape sequence value is not a valid char",
+            ErrorKind::Unrepresentable => "value cant be represented as a token",
+            ErrorKind::BracketNotFound => "no bracket found",
+            ErrorKind::StringNotFound => "no string quote found",
+            ErrorKind::InvalidValue => "invalid value",
+            ErrorKind::InvalidNumber => "invalid number",
+            ErrorKind::NumberOutOfRange => "number out of range",
+        }
+    }
+
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// The error returned by nexttok_ex and the other tokenising methods of TStr.
+///
+/// The positions are relative to the original string slice given to TStr,
+/// and not to what is remaining in it currently. In case of unterminated
+/// blocks, the position is that of the char which opened the block.
+///
+pub struct Error {
+    /// What went wrong
+    pub kind: ErrorKind,
+    /// The offending char
+    pub ch: char,
+    /// The char index of the offending char
+    pub ipos: usize,
+    /// The byte offset of the offending char
+    pub chpos: usize,
+    /// The partial token, which was dropped because of the error
+    pub tok: String,
+    /// The byte offset of the char which opened the block involved in the error, if any.
+    /// ie the opening bracket wrt MismatchedBracket, or the opening quote/bracket/comment wrt
+    /// UnterminatedString/UnterminatedBracket/UnterminatedComment.
+    pub blockpos: Option<usize>,
+    /// The byte range of the token involved in the error, if any.
+    /// ie the token which couldnt be converted to the requested type.
+    pub span: Option<Range<usize>>,
+}
+
+impl Error {
+
+    pub fn new(kind: ErrorKind, ch: char, ipos: usize, chpos: usize, tok: String) -> Error {
+        Error {
+            kind,
+            ch,
+            ipos,
+            chpos,
+            tok,
+            blockpos: None,
+            span: None,
+        }
+    }
+
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}] @ {} (byte {}), partial token [{}]", self.kind, self.ch.escape_debug(), self.ipos, self.chpos, self.tok)?;
+        if let Some(blockpos) = self.blockpos {
+            write!(f, ", block opened @ byte {}", blockpos)?;
+        }
+        if let Some(span) = &self.span {
+            write!(f, ", token @ bytes {}..{}", span.start, span.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
@@ -3,19 +3,41 @@
 //! HanishKVC, 2022
 //!
 
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt;
+use std::iter::FusedIterator;
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
 
 
 pub mod util;
+pub mod error;
+pub mod reader;
+pub mod tree;
+pub mod diag;
+pub mod location;
 mod nexttoken;
-
-
-#[derive(Debug, Clone)]
+mod quote;
+mod typed;
+mod recover;
+mod tstring;
+mod config;
+
+pub use error::{Error, ErrorKind};
+pub use reader::{TReader, ReaderToken, ReaderError};
+pub use tree::TokenTree;
+pub use recover::Recovery;
+pub use location::{LineMap, Location};
+pub use tstring::{TString, TStringTokens};
+pub use config::TConfig;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 ///
 /// Control some of the characteristics of tokenisation, by updating
-/// the below flags, as needed wrt the TStr instance that will be used
-/// for tokenisation.
+/// the below flags, as needed wrt the TStrX (or TStr::config_update)
+/// used to setup the config of the TStr that will be used for tokenisation.
 ///
 pub struct Flags {
     /// If spaces should be trimmed
@@ -23,6 +45,15 @@ pub struct Flags {
     /// Should any escape sequences found during tokenising should be
     /// processed/expanded into the special/non special char represented by them.
     pub escapesequences_expand: bool,
+    /// If hex escape sequences (\xHH or \x{H..}) should be expanded, when
+    /// escapesequences_expand is set.
+    pub escapesequences_hex: bool,
+    /// If octal escape sequences (\O, \OO or \OOO, with a value upto \377) should
+    /// be expanded, when escapesequences_expand is set.
+    pub escapesequences_octal: bool,
+    /// If unicode escape sequences (\uHHHH or \u{H..}) should be expanded, when
+    /// escapesequences_expand is set.
+    pub escapesequences_unicode: bool,
     /// Do block tokens require user specified delim at end
     /// or is block token specific end delimiter good enough
     pub blocktok_dlimuser_endreqd: bool,
@@ -39,6 +70,17 @@ pub struct Flags {
     /// NOTE: There cant be space between the text prefix and 1st opening bracket
     /// if space is a delimiter.
     pub mainbracket_beginprefixed: bool,
+    /// If all the bracket types should be tracked using a stack, so that brackets of
+    /// different types within a block token should be properly nested and balanced.
+    /// Mismatched closing brackets are reported as MismatchedBracket errors.
+    pub bracket_strict: bool,
+    /// If block comments can contain other block comments (of the same type) within them.
+    pub comment_block_nestable: bool,
+    /// If comments should be returned as TokenType::Comment tokens, instead of being skipped.
+    pub comments_retain: bool,
+    /// If the tokens should carry their raw source text, along with the mapping from
+    /// their (possibly cooked) text to the source text (Token::rawmap).
+    pub cooked_map: bool,
     /// Explicit trim at end, This will be useful
     /// when a Non space delim is used and there is spaces before the delim
     trim_atend: bool,
@@ -51,11 +93,18 @@ impl Flags {
         Flags {
             trim: trim,
             escapesequences_expand: escapesequences,
+            escapesequences_hex: false,
+            escapesequences_octal: false,
+            escapesequences_unicode: false,
             blocktok_dlimuser_endreqd: blocktokdelimited,
             stringquotes_retain: retainquotes,
             string_canbe_asubpart: stringasubpart,
             mainbracket_beginstandalone: bracketstandalone,
             mainbracket_beginprefixed: bracketprefixed,
+            bracket_strict: false,
+            comment_block_nestable: false,
+            comments_retain: false,
+            cooked_map: false,
             trim_atend: true,
         }
     }
@@ -65,11 +114,18 @@ impl Flags {
         Flags {
             trim: true,
             escapesequences_expand: true,
+            escapesequences_hex: false,
+            escapesequences_octal: false,
+            escapesequences_unicode: false,
             blocktok_dlimuser_endreqd: true,
             stringquotes_retain: true,
             string_canbe_asubpart: false,
             mainbracket_beginprefixed: true,
             mainbracket_beginstandalone: true,
+            bracket_strict: false,
+            comment_block_nestable: false,
+            comments_retain: false,
+            cooked_map: false,
             trim_atend: true,
         }
     }
@@ -77,7 +133,7 @@ impl Flags {
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// The structure used to maintain the list of delimiters that will be
 /// used by the tokenisation logic.
 pub struct Delimiters {
@@ -85,12 +141,19 @@ pub struct Delimiters {
     pub escseq: char,
     /// The space char
     pub space: char,
-    /// The char used to demarcate/enclose multi word string token
-    pub string: char,
-    /// The bracket start and end chars
-    pub bracket: (char, char),
-    /// A optional additional bracket type
-    pub obracket: Option<(char,char)>,
+    /// The markers (one or more chars each) used to demarcate/enclose multi word string token.
+    /// Each of them is a independent kind of string quote, ie a string opened by one of them,
+    /// can be closed only by the same one.
+    pub strings: Vec<String>,
+    /// The list of bracket types, each specified using its start and end markers
+    /// (one or more chars each)
+    pub brackets: Vec<(String, String)>,
+    /// The markers (one or more chars each) which begin a line comment, ie the rest of
+    /// the line (till newline) is a comment.
+    pub comments_line: Vec<String>,
+    /// The list of block comment types, each specified using its start and end markers
+    /// (one or more chars each)
+    pub comments_block: Vec<(String, String)>,
 }
 
 impl Delimiters {
@@ -99,23 +162,189 @@ impl Delimiters {
         Delimiters {
             escseq: '\\',
             space: ' ',
-            string: '"',
-            bracket: ('(',')'),
-            obracket: None,
+            strings: vec!["\"".to_string()],
+            brackets: vec![("(".to_string(), ")".to_string())],
+            comments_line: Vec::new(),
+            comments_block: Vec::new(),
         }
     }
 
 }
 
 
-#[derive(Debug)]
+///
+/// The delimiter specified by the user wrt the tokenising methods, ie a char
+/// or a string slice (of one or more chars). It is used as is, without having
+/// to allocate a string for it, wrt each token.
+///
+pub trait DelimDef {
+    /// Return the delimiter as a string slice, using the given buffer if required.
+    fn dlim_str<'b>(&'b self, buf: &'b mut [u8; 4]) -> &'b str;
+}
+
+impl DelimDef for char {
+    fn dlim_str<'b>(&'b self, buf: &'b mut [u8; 4]) -> &'b str {
+        self.encode_utf8(buf)
+    }
+}
+
+impl DelimDef for str {
+    fn dlim_str<'b>(&'b self, _buf: &'b mut [u8; 4]) -> &'b str {
+        self
+    }
+}
+
+impl DelimDef for String {
+    fn dlim_str<'b>(&'b self, _buf: &'b mut [u8; 4]) -> &'b str {
+        self.as_str()
+    }
+}
+
+impl<D: DelimDef + ?Sized> DelimDef for &D {
+    fn dlim_str<'b>(&'b self, buf: &'b mut [u8; 4]) -> &'b str {
+        (**self).dlim_str(buf)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// Used to specify the type of token identified and returned by nexttok_ex
 pub enum TokenType {
     Unknown,
     Normal,
-    String,
+    /// The index of the string quote kind (wrt Delimiters::strings) used by the string
+    String(usize),
     BracketStandalone,
     BracketPrefixed,
+    /// A line or block comment, returned only if comments_retain flag is set
+    Comment,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// A run of the text of a token, along with the part of the original string it came from.
+///
+/// A literal run (ie copied as is from the source) has the same length for both
+/// the ranges. Else the run is a single char, which replaces a escape sequence.
+///
+pub struct CookedSeg {
+    /// The byte range wrt the text of the token
+    pub cooked: Range<usize>,
+    /// The byte range wrt the original string
+    pub raw: Range<usize>,
+}
+
+impl CookedSeg {
+
+    /// If the run is copied as is from the source
+    pub fn is_literal(&self) -> bool {
+        self.cooked.len() == self.raw.len()
+    }
+
+    /// Return the runs, which correspond to the given part of the text, with their cooked
+    /// ranges made relative to it, and their raw ranges offset by the given base.
+    pub(crate) fn clip(segs: &[CookedSeg], cooked: Range<usize>, base: usize) -> Vec<CookedSeg> {
+        let (cstart, cend) = (cooked.start, cooked.end);
+        segs.iter().filter(|seg| (seg.cooked.end > cstart) && (seg.cooked.start < cend)).map(|seg| {
+            let (mut start, mut end) = (seg.cooked.start, seg.cooked.end);
+            let (mut rstart, mut rend) = (seg.raw.start, seg.raw.end);
+            if seg.is_literal() {
+                rstart += cstart.saturating_sub(start);
+                rend -= end.saturating_sub(cend);
+                start = start.max(cstart);
+                end = end.min(cend);
+            }
+            CookedSeg { cooked: start-cstart..end-cstart, raw: base+rstart..base+rend }
+        }).collect()
+    }
+
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The raw source text of a token, along with how its text maps to it.
+pub struct RawMap<'a> {
+    /// The part of the original string, which corresponds to the token (ie wrt its span)
+    pub raw: Cow<'a, str>,
+    /// The runs making up the text of the token, in order. The parts of the source
+    /// which dont contribute to the text (like stripped quotes) are not covered.
+    pub segs: Vec<CookedSeg>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// A token extracted from a TStr, along with its type and where it was
+/// found in the original string/line.
+///
+/// All the ranges are byte offsets into the original string slice given
+/// to TStr (through from_str/from_str_ex/set_str), and not into what is
+/// remaining in the TStr currently.
+///
+/// The text of the token borrows from the original string, if it is the
+/// same as the corresponding part of the original string. Only if it differs
+/// (bcas of escape sequence expansion, quote stripping, ...), is a new string
+/// allocated for it.
+///
+pub struct Token<'a> {
+    /// The text of the token, after any escape sequence expansion, quote stripping, ...
+    pub text: Cow<'a, str>,
+    /// The type of the token
+    pub toktype: TokenType,
+    /// The part of the original string, which corresponds to the token
+    pub span: Range<usize>,
+    /// The spaces just before the token, which were trimmed out. With a non space
+    /// delimiter, it extends back till the delimiter which ended the previous token.
+    pub lead: Range<usize>,
+    /// The spaces just after the token, which were trimmed out
+    pub trail: Range<usize>,
+    /// The raw source text and the mapping of the text to it, if cooked_map flag is set
+    pub rawmap: Option<RawMap<'a>>,
+}
+
+impl<'a> Token<'a> {
+
+    /// Convert into a token, which doesnt borrow from the original string
+    pub fn into_owned(self) -> Token<'static> {
+        Token {
+            text: Cow::Owned(self.text.into_owned()),
+            toktype: self.toktype,
+            span: self.span,
+            lead: self.lead,
+            trail: self.trail,
+            rawmap: self.rawmap.map(|rawmap| RawMap {
+                raw: Cow::Owned(rawmap.raw.into_owned()),
+                segs: rawmap.segs,
+            }),
+        }
+    }
+
+    ///
+    /// Return the byte range wrt the original string, of the source text which gave
+    /// the char at the given char index in the text of the token. ie the char itself
+    /// or the escape sequence which was expanded into it.
+    ///
+    /// Available only if the token was extracted with the cooked_map flag set.
+    ///
+    pub fn raw_range(&self, ich: usize) -> Option<Range<usize>> {
+        let segs = &self.rawmap.as_ref()?.segs;
+        let (pos, ch) = self.text.char_indices().nth(ich)?;
+        let iseg = segs.partition_point(|seg| seg.cooked.end <= pos);
+        let seg = segs.get(iseg)?;
+        if seg.is_literal() {
+            let start = seg.raw.start + (pos - seg.cooked.start);
+            return Some(start..start+ch.len_utf8());
+        }
+        Some(seg.raw.clone())
+    }
+
+}
+
+impl<'a> fmt::Display for Token<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
 }
 
 
@@ -134,14 +363,19 @@ pub enum TokenType {
 /// * block tokens, made up of tokens within them, these could include
 ///   * quoted string block with spaces/escaped-delimiters/... in between, if required
 ///     * uses the same string quote char for begin and end of the string block
+///     * multiple kinds of string quotes can be active at the same time, each of
+///       them being closed only by the same kind of quote, which opened it.
 ///   * bracketed content block
 ///     * contains a seperate begin and end bracket char wrt the block
+///     * any number of bracket types can be configured, the outermost bracket
+///       type of a block is what is used to demarcate it.
 ///     * bracketed content block can contain other bracketed content blocks
 ///       within them, to what ever depth required.
 ///     * The logic will try to match opening and its corresponding closing bracket,
 ///       so that a valid block of text is returned as the token.
-///       If there is a string token within a bracketed block, containing the bracket
-///       chars, which may not be balanced, they should be escaped.
+///       Any quoted strings within a bracketed block are tracked, so that bracket chars
+///       within such strings are not considered wrt matching the brackets. So they
+///       need not be escaped.
 ///     * There are two types of bracketed block tokens
 ///       * BracketStandalone: those starting with begin-bracket-char at the begining of the token
 ///       * BracketPrefixed: these need to contain some textual prefix before the begin-bracket-char
@@ -149,17 +383,31 @@ pub enum TokenType {
 ///         a given bracketed block could be either Standalone or Prefixed type.
 ///
 pub struct TStr<'a> {
+    /// The original string slice given to this TStr
+    theOrig: &'a str,
+    /// What is remaining in the string slice, to be tokenised
     theStr: &'a str,
     /// The amount of space trimmed at the begining of the string
     trimmedPrefixCnt: isize,
     /// The amount of space trimmed at the end of the string
     trimmedSuffixCnt: isize,
-    /// Maintain the set of supported escape sequences and the underlying expanded char.
-    pub escSeqMap: HashMap<char, char>,
-    /// Control the tokenisation characteristics
-    pub flags: Flags,
-    /// Delimiters used to demarcate the tokens
-    pub delims: Delimiters,
+    /// The tokenisation characteristics (delims, escseqs, flags), shared with others
+    cfg: Arc<TConfig>,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// A saved position of a TStr, returned by TStr::checkpoint, to rewind it to later.
+///
+/// It is valid only wrt the TStr from which it was got, as long as the TStr
+/// is not switched to a different string/line (using set_str).
+///
+pub struct Checkpoint {
+    start: usize,
+    end: usize,
+    prefixcnt: isize,
+    suffixcnt: isize,
 }
 
 
@@ -173,13 +421,22 @@ impl<'a> TStr<'a> {
     /// If btrim is set, then trim the string
     ///
     pub fn from_str_ex(s: &'a str, btrim: bool, delims: Delimiters, escseqs: HashMap<char, char>, flags: Flags) -> TStr<'a> {
+        Self::from_str_cfg(s, btrim, Arc::new(TConfig::new(delims, escseqs, flags)))
+    }
+
+    ///
+    /// Create a new instance of TStr for the given string slice,
+    /// which uses (shares) the given config.
+    ///
+    /// If btrim is set, then trim the string
+    ///
+    pub fn from_str_cfg(s: &'a str, btrim: bool, cfg: Arc<TConfig>) -> TStr<'a> {
         let mut tstr = TStr {
+            theOrig: s,
             theStr: s,
             trimmedPrefixCnt: -1,
             trimmedSuffixCnt: -1,
-            escSeqMap: escseqs,
-            flags: flags,
-            delims: delims,
+            cfg,
         };
         if btrim {
             tstr.trim();
@@ -192,13 +449,38 @@ impl<'a> TStr<'a> {
     ///
     /// If btrim is set, then trim the string
     pub fn from_str(s: &'a str, btrim: bool) -> TStr<'a> {
-        Self::from_str_ex(s, btrim, Delimiters::default(), TStrX::escseqs_default(), Flags::default())
+        Self::from_str_cfg(s, btrim, TConfig::shared_default())
+    }
+
+    /// The config (tokenisation characteristics) used by this TStr
+    pub fn config(&self) -> &Arc<TConfig> {
+        &self.cfg
+    }
+
+    /// Switch this TStr to use the given config
+    pub fn set_config(&mut self, cfg: Arc<TConfig>) {
+        self.cfg = cfg;
+    }
+
+    ///
+    /// Update the tokenisation characteristics used by this TStr, through the TStrX
+    /// given to the passed function, which starts with the current characteristics.
+    ///
+    /// NOTE: This creates a new config for this TStr, so if the same characteristics
+    /// are required across multiple TStr's, it is better to setup a TStrX and share
+    /// its config.
+    ///
+    pub fn config_update(&mut self, f: impl FnOnce(&mut TStrX)) {
+        let mut tstrx = TStrX::from_config(&self.cfg);
+        f(&mut tstrx);
+        self.cfg = tstrx.config();
     }
 
     /// Allow an existing TStr to be used wrt a new string/line
     ///
     /// If btrim is set, then trim the updated TStr
     pub fn set_str(&mut self, s: &'a str, btrim: bool) {
+        self.theOrig = s;
         self.theStr = s;
         self.trimmedPrefixCnt = -1;
         self.trimmedSuffixCnt = -1;
@@ -226,6 +508,51 @@ impl<'a> TStr<'a> {
         &self.theStr
     }
 
+    /// The byte offset of the current string slice, wrt the original string slice.
+    /// NOTE: theStr is always a sub slice of theOrig.
+    fn orig_offset(&self) -> usize {
+        self.theStr.as_ptr() as usize - self.theOrig.as_ptr() as usize
+    }
+
+    /// The original string slice given to this TStr, irrespective of how much of it
+    /// has been tokenised/trimmed till now.
+    pub fn original(&self) -> &'a str {
+        self.theOrig
+    }
+
+    /// The byte offset wrt the original string slice, till where it has been
+    /// consumed (tokenised/trimmed) till now.
+    pub fn consumed(&self) -> usize {
+        self.orig_offset()
+    }
+
+    /// Return a LineMap over the original string slice, to map the positions in the
+    /// errors and the spans in the tokens (which are wrt it), into line and columns.
+    pub fn line_map(&self) -> LineMap<'a> {
+        LineMap::new(self.theOrig)
+    }
+
+    /// Save the current position, so that one can rewind to it later.
+    pub fn checkpoint(&self) -> Checkpoint {
+        let start = self.orig_offset();
+        Checkpoint {
+            start,
+            end: start + self.theStr.len(),
+            prefixcnt: self.trimmedPrefixCnt,
+            suffixcnt: self.trimmedSuffixCnt,
+        }
+    }
+
+    /// Restore the position saved in the given checkpoint.
+    /// A checkpoint which doesnt fit the current original string slice is ignored.
+    pub fn rewind(&mut self, cp: Checkpoint) {
+        if let Some(s) = self.theOrig.get(cp.start..cp.end) {
+            self.theStr = s;
+            self.trimmedPrefixCnt = cp.prefixcnt;
+            self.trimmedSuffixCnt = cp.suffixcnt;
+        }
+    }
+
     pub fn trimmed_prefix_cnt_raw(&self) -> isize {
         self.trimmedPrefixCnt
     }
@@ -279,7 +606,7 @@ impl<'a> TStr<'a> {
     ///
     pub fn drop_adjust(&mut self, nexttokpos: usize) {
         if nexttokpos >= self.theStr.len() {
-            self.theStr = &"";
+            self.theStr = &self.theStr[self.theStr.len()..];
         } else {
             self.theStr = &self.theStr[nexttokpos..];
         }
@@ -289,8 +616,12 @@ impl<'a> TStr<'a> {
     /// Extract the next token string belonging to this TStr instance,
     /// and inturn its token type info.
     ///
-    /// User can specify the delimiter between the tokens.
+    /// User can specify the delimiter between the tokens, as a char or a string slice
+    /// (see DelimDef).
     /// * space ' ' or comma ',' could be commonly useful delimiters.
+    /// * the delimiter can also be made up of multiple chars, like "::" or "=>".
+    ///   If some of the string/bracket markers share a prefix with it, then the
+    ///   longest marker matching at a given position is used.
     ///
     /// The user specified delimiter will be trimmed out.
     ///
@@ -306,11 +637,22 @@ impl<'a> TStr<'a> {
     ///   * however by default, the starting opening bracket should be prefixed
     ///     with some alphanumeric text. (user can change this behaviour, if reqd)
     ///
-    /// If any error identified while scanning for the token, a error message
-    /// is returned to the caller, while parallley dropping the token with error,
-    /// so that next call to this will potentially retrieve a valid token, if any
-    /// still in the string/line. The partial dropped token is also returned, as
-    /// part of a tuple, along with the err message. ie (ErrMsg, PartialTok).
+    /// If any error identified while scanning for the token, a Error is returned
+    /// to the caller, while parallley dropping the token with error, so that next
+    /// call to this will potentially retrieve a valid token, if any still in the
+    /// string/line. The Error contains the kind of error, the offending char and
+    /// its position, as well as the partial dropped token.
+    ///
+    /// If the string/line ends before a string or bracketed block token is closed,
+    /// a UnterminatedString or UnterminatedBracket error is returned, with the
+    /// position of the char which opened the block.
+    ///
+    /// If line/block comment markers are configured in delims, comments outside of
+    /// strings are skipped, or returned as TokenType::Comment tokens if the
+    /// comments_retain flag is set. A line comment also ends the current token.
+    /// If only skipped comments (and spaces) remain, a empty Unknown token is returned,
+    /// while the methods which get all the tokens (tokens_vec, tokens, ...) dont return
+    /// any token wrt them.
     ///
     /// If a escape sequence is found anywhere other than begining of the token,
     /// it will be processed/expanded, if requested.
@@ -318,30 +660,128 @@ impl<'a> TStr<'a> {
     /// If user requests trimming, then any spaces before and after the token
     /// will be trimmed out.
     ///
-    pub fn nexttok_ex(&mut self, dlimdef: char, btrim: bool) -> Result<(String, TokenType), (String, String)> {
-        let mut flags = self.flags.clone();
+    /// The text of the returned token borrows from the string/line being tokenised,
+    /// unless escape sequence expansion or quote stripping has changed it.
+    ///
+    pub fn nexttok_ex(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<Token<'a>, Error> {
+        let mut dlimbuf = [0; 4];
+        let dlimdef = dlimdef.dlim_str(&mut dlimbuf);
+        match self.nexttok_opt(dlimdef, btrim)? {
+            Some(token) => Ok(token),
+            None => Ok(self.token_empty()),
+        }
+    }
+
+    /// The empty token at the current position
+    fn token_empty(&self) -> Token<'a> {
+        let pos = self.orig_offset();
+        let rawmap = if self.cfg.flags.cooked_map { Some(RawMap { raw: Cow::Borrowed(""), segs: Vec::new() }) } else { None };
+        Token {
+            text: Cow::Borrowed(""),
+            toktype: TokenType::Unknown,
+            span: pos..pos,
+            lead: pos..pos,
+            trail: pos..pos,
+            rawmap,
+        }
+    }
+
+    ///
+    /// Extract the next token, like nexttok_ex, except that None is returned, if only
+    /// skipped comments (and spaces) remained, which are consumed.
+    ///
+    pub(crate) fn nexttok_opt(&mut self, dlimdef: &str, btrim: bool) -> Result<Option<Token<'a>>, Error> {
+        // The scan always completes, when no more text can follow
+        self.nexttok_resume(dlimdef, btrim, &mut None, None, None, None).unwrap_or(Ok(None))
+    }
+
+    ///
+    /// Extract the next token, like nexttok_opt, recording the markers (brackets, quotes
+    /// and delimiters) within its bracket blocks, when building a tree of tokens. The given
+    /// flags, if any, are used instead of the flags in the config.
+    ///
+    pub(crate) fn nexttok_tree(&mut self, dlimdef: &str, btrim: bool, marks: &mut Vec<nexttoken::BracketMark>, flags: Option<Flags>) -> Result<Option<Token<'a>>, Error> {
+        self.nexttok_resume(dlimdef, btrim, &mut None, None, Some(marks), flags).unwrap_or(Ok(None))
+    }
+
+    ///
+    /// Extract the next token, like nexttok_opt, continuing from the given scan, if any.
+    ///
+    /// If more text can follow the current string, then more should contain the byte
+    /// position (wrt the current string), till which the chars can be scanned. In which
+    /// case, if the token is not yet complete, ie if the scan reached the given position
+    /// (before the end of the string), or the string ended within a string/bracket block
+    /// (or block comment), None is returned, with the state of the scan saved in scan.
+    /// The scan can then be continued, once more text is appended to the string.
+    ///
+    /// On a error, the state of the scan (wrt the current string, as it was before the
+    /// call) is saved in scan, so that the scan can be continued past the error, if required.
+    ///
+    /// The vector to record the markers within bracket blocks into, if any, as well as the flags to use instead of the
+    /// flags in the config (say a variant of them), if any, are used only wrt a fresh scan.
+    ///
+    pub(crate) fn nexttok_resume(&mut self, dlimdef: &str, btrim: bool, scan: &mut Option<nexttoken::Scan>, more: Option<usize>, marks: Option<&mut Vec<nexttoken::BracketMark>>, flags: Option<Flags>) -> Option<Result<Option<Token<'a>>, Error>> {
+        let thestr = self.theStr;
+        let cfg = self.cfg.clone();
+        let mut flags = flags.unwrap_or_else(|| cfg.flags.clone());
         flags.trim = btrim;
-        let mut ctxt = nexttoken::Ctxt::new(self.theStr, dlimdef, self.escSeqMap.clone(), flags);
-        let vchartypes = nexttoken::VCharTypes::from_delimiters(&self.delims, Some(dlimdef));
+        let (btrim_atend, bcooked_map) = (flags.trim_atend, flags.cooked_map);
+        let mut ctxt = match scan.take() {
+            Some(saved) => nexttoken::Ctxt::resume(thestr, dlimdef, &cfg.escseqs, saved),
+            None => nexttoken::Ctxt::new(thestr, dlimdef, cfg.delims.space, &cfg.escseqs, flags),
+        };
+        if let Some(marks) = marks {
+            ctxt.marks_record(self.orig_offset(), marks);
+        }
+        let vchartypes = &cfg.vchartypes;
+        let normaldelim = if ctxt.bdlimnormal { Some(dlimdef) } else { None };
+        let dlimch = normaldelim.and_then(|dlim| dlim.chars().next());
         let mut bdone = false;
-        for i in 0..ctxt.vchars.len() {
-            (ctxt.chpos, ctxt.ch) = ctxt.vchars[i];
+        let (istart, chstart) = ctxt.resumepos;
+        let limit = more.unwrap_or(thestr.len());
+        for (i, (chpos, ch)) in thestr[chstart..].char_indices().enumerate() {
+            let (i, chpos) = (istart + i, chstart + chpos);
+            if chpos >= limit {
+                break;
+            }
+            ctxt.resumepos = (i + 1, chpos + ch.len_utf8());
+            (ctxt.chpos, ctxt.ch) = (chpos, ch);
+            if ctxt.chpos < ctxt.skipto {
+                continue;
+            }
             ctxt.ipos = i;
-            for vct in &vchartypes.vct {
+            ctxt.mklen = 0;
+            // Only the chartypes, which can act on the current char, need to be tried.
+            // A empty normal delimiter matches at any char.
+            let class = vchartypes.class(ch);
+            let bdn = normaldelim.is_some() && (dlimch.is_none() || (dlimch == Some(ch)));
+            let chain = if ctxt.bescape {
+                &vchartypes.vescape
+            } else {
+                if class.bmarker || bdn {
+                    ctxt.mklen = vchartypes.marker_len(&thestr[ctxt.chpos..], normaldelim);
+                }
+                if bdn { &class.vctdn } else { &class.vct }
+            };
+            for vct in chain.iter().map(|ict| &vchartypes.vct[*ict]) {
                 let act = vct.process_char(&mut ctxt);
                 if act.is_err() {
-                    let nexti = i + 1;
-                    let nexttokpos;
-                    if nexti < ctxt.vchars.len() {
-                        nexttokpos = ctxt.vchars[nexti].0;
-                    } else {
-                        nexttokpos = self.theStr.len();
+                    let nexttokpos = ctxt.chpos + ctxt.unit_len();
+                    let kind = act.unwrap_err();
+                    let mut err = self.error_at(kind, ctxt.ch, ctxt.ipos, ctxt.chpos, ctxt.tok_take().into_owned());
+                    if kind == ErrorKind::MismatchedBracket {
+                        err.blockpos = Some(self.orig_offset() + ctxt.blockbeg.1);
                     }
                     self.drop_adjust(nexttokpos);
-                    return Err((format!("TStr:NextTok:{}", act.unwrap_err()), ctxt.tok.to_string()))
+                    ctxt.skipto = nexttokpos;
+                    *scan = Some(ctxt.suspend());
+                    return Some(Err(err));
                 }
                 match act.unwrap() {
-                    nexttoken::Action::NextChar => break,
+                    nexttoken::Action::NextChar => {
+                        ctxt.skipto = ctxt.chpos + ctxt.unit_len();
+                        break;
+                    }
                     nexttoken::Action::ContinueChain => continue,
                     nexttoken::Action::DoneBreak => bdone=true,
                 }
@@ -350,15 +790,107 @@ impl<'a> TStr<'a> {
                 break;
             }
         }
+        if !bdone && more.is_some() && ((ctxt.resumepos.1 < thestr.len()) || ctxt.unterminated().is_some()) {
+            *scan = Some(ctxt.suspend());
+            return None;
+        }
         if !bdone {
             ctxt.nextpos = self.len();
+            if let Some(commentpos) = ctxt.comment_deferred() {
+                ctxt.nextpos = commentpos;
+            }
+            if let Some((kind, (ipos, chpos, ch))) = ctxt.unterminated() {
+                let mut err = self.error_at(kind, ch, ipos, chpos, ctxt.tok_take().into_owned());
+                err.blockpos = Some(err.chpos);
+                self.drop_adjust(ctxt.nextpos);
+                return Some(Err(err));
+            }
+            if ctxt.comment_only() {
+                self.drop_adjust(ctxt.nextpos);
+                return Some(Ok(None));
+            }
         }
+        let base = self.orig_offset();
         self.drop_adjust(ctxt.nextpos);
-        // trim spaces that can be at the end, when a non space dlimdef is used
-        if btrim && self.flags.trim_atend {
-            ctxt.tok = ctxt.tok.trim().to_string();
+        // Work out the span of the token, as well as the spaces trimmed on either side
+        let consumed = ctxt.nextpos.min(thestr.len());
+        let (mut tstart, mut tend) = match ctxt.tokspan {
+            Some(tokspan) => tokspan,
+            None => {
+                let pos = consumed - thestr[..consumed].trim_start().len();
+                (pos, pos)
+            }
+        };
+        // trim spaces that can be at the end, when a non space dlimdef is used.
+        // However the ends of a string token, which are its quotes in the source text,
+        // are not trimmed, so that spaces within the quotes are kept, even if the quotes
+        // are not retained.
+        let mut tok = ctxt.tok_take();
+        let mut ctrim = (0, tok.len());
+        if btrim && btrim_atend {
+            let raw = &thestr[tstart..tend];
+            let (bstart, bend) = match ctxt.toktype {
+                TokenType::String(sid) => {
+                    let quote = self.cfg.delims.strings[sid].as_str();
+                    (!raw.starts_with(quote), (raw.len() < 2*quote.len()) || !raw.ends_with(quote))
+                }
+                _ => (true, true),
+            };
+            let start = if bstart { tok.len() - tok.trim_start().len() } else { 0 };
+            let end = if bend { tok.trim_end().len() } else { tok.len() };
+            ctrim = (start, end.max(start));
+            tok = match tok {
+                Cow::Borrowed(stok) => Cow::Borrowed(&stok[ctrim.0..ctrim.1]),
+                Cow::Owned(stok) => {
+                    if ctrim == (0, stok.len()) {
+                        Cow::Owned(stok)
+                    } else {
+                        Cow::Owned(stok[ctrim.0..ctrim.1].to_string())
+                    }
+                }
+            };
+            tstart += raw.len() - raw.trim_start().len();
+            tend = tstart + raw.trim().len();
+        }
+        if ctrim.0 > 0 {
+            ctxt.marks_trimmed(ctrim.0);
+        }
+        // Map the runs of the token text, which remain after trimming, to the original string
+        let rawmap = if bcooked_map {
+            let segs = CookedSeg::clip(&ctxt.segs, ctrim.0..ctrim.1, base);
+            Some(RawMap { raw: Cow::Borrowed(&thestr[tstart..tend]), segs })
+        } else {
+            None
+        };
+        let mut lstart = base + thestr[..tstart].trim_end().len();
+        if (lstart == base) && normaldelim.is_some() {
+            // The spaces after the delimiter, which ended the previous token, were consumed
+            // along with it, so the lead extends back till the delimiter.
+            for (pos, ch) in self.theOrig[..base].char_indices().rev() {
+                if !ch.is_whitespace() || self.theOrig[..pos+ch.len_utf8()].ends_with(dlimdef) {
+                    break;
+                }
+                lstart = pos;
+            }
         }
-        return Ok((ctxt.tok, ctxt.toktype));
+        let tail = &thestr[tend..consumed.max(tend)];
+        let tlen = tail.len() - tail.trim_start().len();
+        return Some(Ok(Some(Token {
+            text: tok,
+            toktype: ctxt.toktype.clone(),
+            span: base+tstart..base+tend,
+            lead: lstart..base+tstart,
+            trail: base+tend..base+tend+tlen,
+            rawmap,
+        })));
+    }
+
+    /// Create a Error, with its positions adjusted to be wrt the original string slice,
+    /// from the given positions, which are relative to the current string slice.
+    fn error_at(&self, kind: ErrorKind, ch: char, ipos: usize, chpos: usize, tok: String) -> Error {
+        let base = self.orig_offset();
+        let ibase = self.theOrig[..base].chars().count();
+        Error::new(kind, ch, ibase+ipos, base+chpos, tok)
     }
 
     ///
@@ -366,13 +898,25 @@ impl<'a> TStr<'a> {
     ///
     /// NOTE: Look at the doc related to nexttok_ex for more details.
     ///
-    pub fn nexttok(&mut self, dlimdef: char, btrim: bool) -> Result<String, (String, String)> {
+    pub fn nexttok(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<Cow<'a, str>, Error> {
         let gotr = self.nexttok_ex(dlimdef, btrim);
         if gotr.is_err() {
             return Err(gotr.unwrap_err());
         }
         let gotr = gotr.unwrap();
-        return Ok(gotr.0);
+        return Ok(gotr.text);
+    }
+
+    ///
+    /// Look at the next token, without consuming it, ie the position is left as is.
+    ///
+    /// NOTE: Look at the doc related to nexttok_ex for more details.
+    ///
+    pub fn peek_tok(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<Token<'a>, Error> {
+        let cp = self.checkpoint();
+        let gotr = self.nexttok_ex(dlimdef, btrim);
+        self.rewind(cp);
+        gotr
     }
 
     ///
@@ -400,15 +944,48 @@ impl<'a> TStr<'a> {
     /// block is found, it will be treated has a multi-token-based-block-token
     /// on its own.
     ///
-    pub fn tokens_vec(&mut self, dlimdef: char, btrim: bool, bcontinue_onerr: bool) -> Result<Vec<String>, String> {
+    pub fn tokens_vec(&mut self, dlimdef: impl DelimDef, btrim: bool, bcontinue_onerr: bool) -> Result<Vec<Cow<'a, str>>, Error> {
+        let vtoks = self.tokens_vec_ex(dlimdef, btrim, bcontinue_onerr)?;
+        Ok(vtoks.into_iter().map(|tok| tok.text).collect())
+    }
+
+    ///
+    /// Get a iterator over the tokens in the current string/line, which returns
+    /// each token (or the error if any wrt it) in turn, till the string/line is
+    /// fully consumed.
+    ///
+    /// The iterator works by calling nexttok_ex repeatedly, so the span of the
+    /// tokens continue to be wrt the original string, even as this TStr is
+    /// adjusted to drop the tokens already returned.
+    ///
+    /// After a error, the rest of the token in which it was found is skipped (like
+    /// Recovery::SkipToDelim), so the iterator continues with the next token.
+    ///
+    pub fn tokens<'t, D: DelimDef>(&'t mut self, dlimdef: D, btrim: bool) -> Tokens<'t, 'a, D> {
+        Tokens {
+            tstr: self,
+            dlimdef,
+            btrim,
+        }
+    }
+
+    ///
+    /// Get a vector of all the tokens in the current string/line, along with
+    /// their type and span info.
+    ///
+    /// NOTE: Look at the doc related to tokens_vec for more details.
+    ///
+    pub fn tokens_vec_ex(&mut self, dlimdef: impl DelimDef, btrim: bool, bcontinue_onerr: bool) -> Result<Vec<Token<'a>>, Error> {
+        let mut dlimbuf = [0; 4];
+        let dlimdef = dlimdef.dlim_str(&mut dlimbuf);
         let mut vtoks = Vec::new();
         while self.remaining_len() > 0 {
-            let gottok = self.nexttok(dlimdef, btrim);
+            let gottok = self.nexttok_opt(dlimdef, btrim);
             if gottok.is_err() && !bcontinue_onerr {
-                return Err(format!("TStr:TokensVec:{:?}", gottok.unwrap_err()));
+                return Err(gottok.unwrap_err());
             }
-            if gottok.is_ok() {
-                vtoks.push(gottok.unwrap());
+            if let Ok(Some(tok)) = gottok {
+                vtoks.push(tok);
             }
         }
         Ok(vtoks)
@@ -417,6 +994,39 @@ impl<'a> TStr<'a> {
 }
 
 
+/// The iterator over the tokens in a TStr, returned by TStr::tokens.
+pub struct Tokens<'t, 'a, D: DelimDef> {
+    tstr: &'t mut TStr<'a>,
+    dlimdef: D,
+    btrim: bool,
+}
+
+impl<'t, 'a, D: DelimDef> Iterator for Tokens<'t, 'a, D> {
+    type Item = Result<Token<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut dlimbuf = [0; 4];
+        let dlimdef = self.dlimdef.dlim_str(&mut dlimbuf);
+        while self.tstr.remaining_len() > 0 {
+            let thestr = self.tstr.theStr;
+            let mut scan = None;
+            match self.tstr.nexttok_resume(dlimdef, self.btrim, &mut scan, None, None, None).unwrap_or(Ok(None)) {
+                Ok(None) => continue,
+                Ok(Some(tok)) => return Some(Ok(tok)),
+                Err(err) => {
+                    self.tstr.skip_token(dlimdef, self.btrim, thestr, scan);
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Once the TStr is fully consumed, it remains so.
+impl<'t, 'a, D: DelimDef> FusedIterator for Tokens<'t, 'a, D> {}
+
+
 /// Helper methods, matching similar functionality of strings in general
 impl<'a> TStr<'a> {
 
@@ -428,12 +1038,12 @@ impl<'a> TStr<'a> {
     /// for beyond the 1st token (ie the delimiter could be inside the
     /// 1st token, if it is a block token).
     ///
-    pub fn split_once(&mut self, dlimdef: char) -> Result<(String, String), String> {
+    pub fn split_once(&mut self, dlimdef: impl DelimDef) -> Result<(Cow<'a, str>, &'a str), Error> {
         let gottok = self.nexttok(dlimdef, true);
         if gottok.is_err() {
-            return Err(format!("TStr:SplitOnce:{:?}",gottok.unwrap_err()));
+            return Err(gottok.unwrap_err());
         }
-        return Ok((gottok.unwrap(), self.the_str().to_string()));
+        return Ok((gottok.unwrap(), self.theStr));
     }
 
     ///
@@ -445,20 +1055,48 @@ impl<'a> TStr<'a> {
     /// through the internal string slice. The retrieved tokens could also represent
     /// block type tokens.
     ///
-    pub fn splitn(&mut self, reqcnt: usize, dlimdef: char) -> Result<Vec<String>, String> {
+    pub fn splitn(&mut self, reqcnt: usize, dlimdef: impl DelimDef) -> Result<Vec<Cow<'a, str>>, Error> {
+        let vres = self.splitn_ex(reqcnt, dlimdef)?;
+        Ok(vres.into_iter().map(|tok| tok.text).collect())
+    }
+
+    ///
+    /// Retrieve upto n tokens, along with their type and span info.
+    /// The nth token (if any) will be the remaining part of the string, as is,
+    /// with its token type set to Unknown.
+    ///
+    /// NOTE: Look at the doc related to splitn for more details.
+    ///
+    pub fn splitn_ex(&mut self, reqcnt: usize, dlimdef: impl DelimDef) -> Result<Vec<Token<'a>>, Error> {
+        let mut dlimbuf = [0; 4];
+        let dlimdef = dlimdef.dlim_str(&mut dlimbuf);
         let mut vres = Vec::new();
         for _i in 1..reqcnt {
-            let tok = self.nexttok(dlimdef, true);
-            if tok.is_err() {
-                return Err(format!("TStr:SplitN:{:?}", tok.unwrap_err()));
+            if let Some(tok) = self.nexttok_opt(dlimdef, true)? {
+                vres.push(tok);
             }
-            vres.push(tok.unwrap());
             if self.remaining_len() == 0 {
                 break;
             }
         }
         if self.remaining_len() > 0 {
-            vres.push(self.the_str().to_string())
+            let start = self.orig_offset();
+            let end = start + self.len();
+            let rawmap = if self.cfg.flags.cooked_map {
+                let segs = if start < end { vec![CookedSeg { cooked: 0..end-start, raw: start..end }] } else { Vec::new() };
+                Some(RawMap { raw: Cow::Borrowed(self.theStr), segs })
+            } else {
+                None
+            };
+            vres.push(Token {
+                text: Cow::Borrowed(self.theStr),
+                toktype: TokenType::Unknown,
+                span: start..end,
+                lead: start..start,
+                trail: end..end,
+                rawmap,
+            });
+            self.drop_adjust(self.len());
         }
         Ok(vres)
     }
@@ -486,37 +1124,152 @@ impl<'a> TStr<'a> {
     ///
     /// Assumes that a bracketed block is what is currently stored in this TStr instance,
     /// inturn
-    /// * return any prefix text associated with the bracket
+    /// * return its parts, ie any prefix text associated with the bracket, the type
+    ///   of the bracket and its contents.
     /// * update this TStr instance to contain the string representing
     ///   the contents of the bracket
     ///
-    /// User can specify the type of the begining bracket
+    /// User can specify the type of the begining bracket. If not specified, any of the
+    /// configured bracket types is accepted.
+    ///
+    /// The whole string is validated using the tokeniser, so it should be a single bracket
+    /// block token, ie an optional prefix (which is a valid normal token, without any spaces)
+    /// followed by the bracketed block, whose brackets are balanced (taking into account any
+    /// escaped brackets or brackets within strings), with the matching end bracket at the
+    /// end of the string.
+    ///
+    /// Returns a error, with its positions wrt the original string, if
+    /// * the string doesnt start with a bracketed block token of a configured (or the
+    ///   specified) bracket type (BracketNotFound)
+    /// * the brackets are not balanced (UnterminatedBracket, MismatchedBracket, ...)
+    /// * there is something after the end of the bracketed block (JunkAfterBlockToken)
     ///
-    pub fn peel_bracket(&mut self, bracket_begin: char) -> Result<String, String> {
+    /// This TStr instance is only trimmed, in case of errors.
+    ///
+    pub fn peel_bracket(&mut self, bracket_begin: Option<&str>) -> Result<BracketBlock<'a>, Error> {
         self.trim();
-        let prefixplus = self.theStr.split_once(bracket_begin);
-        if prefixplus.is_none() {
-            return Err(format!("TStr:PeelBracket:{}:from:{}:failed", bracket_begin, self.theStr));
+        let thestr = self.theStr;
+        let base = self.orig_offset();
+        let mut block = self.clone();
+        let mut flags = self.cfg.flags.clone();
+        flags.mainbracket_beginstandalone = true;
+        flags.mainbracket_beginprefixed = true;
+        flags.blocktok_dlimuser_endreqd = true;
+        let mut dlimbuf = [0; 4];
+        let space = self.cfg.delims.space.dlim_str(&mut dlimbuf);
+        let mut marks = Vec::new();
+        let tok = block.nexttok_tree(space, true, &mut marks, Some(flags))?;
+        let mbid = marks.first().and_then(|mark| match mark.kind {
+            nexttoken::MarkKind::BracketBegin(bid) => Some(bid),
+            _ => None,
+        });
+        let (tok, kind) = match (tok, mbid) {
+            (Some(tok), Some(bid)) if bracket_begin.is_none_or(|bb| bb == self.cfg.delims.brackets[bid].0) => (tok, bid),
+            _ => {
+                let ch = thestr.chars().next().unwrap_or(' ');
+                return Err(self.error_at(ErrorKind::BracketNotFound, ch, 0, 0, String::new()));
+            }
+        };
+        if let Some(ch) = block.char_first() {
+            return Err(block.error_at(ErrorKind::JunkAfterBlockToken, ch, 0, 0, tok.text.into_owned()));
         }
-        let prefixplus = prefixplus.unwrap();
-        let smembers = &prefixplus.1[..prefixplus.1.len()-1];
-        self.theStr = smembers;
-        return Ok(prefixplus.0.to_string());
+        let (bbegin, bend) = &self.cfg.delims.brackets[kind];
+        let bpos = marks[0].start - base;
+        let cstart = bpos + bbegin.len();
+        let cend = (tok.span.end - base - bend.len()).max(cstart);
+        self.theStr = &thestr[cstart..cend];
+        Ok(BracketBlock {
+            prefix: Cow::Borrowed(&thestr[..bpos]),
+            kind,
+            contents: Cow::Borrowed(self.theStr),
+        })
     }
 
     ///
-    /// Update this TStr instance to contain the string without its enclosing
-    /// string delimiter.
+    /// Assumes that a quoted string is what is currently stored in this TStr instance,
+    /// inturn
+    /// * return the contents of the string, with any escape sequences expanded, if
+    ///   escapesequences_expand flag is set.
+    /// * update this TStr instance to contain the string without its enclosing
+    ///   string quotes (and with escape sequences as is).
+    ///
+    /// User can specify the string quote. If not specified, the configured string quote
+    /// (longest on a tie), which the string starts with, is used.
+    ///
+    /// The string is validated using the tokeniser, so a escaped quote doesnt end the
+    /// string, and nothing other than spaces can follow the closing quote.
     ///
-    pub fn peel_string(&mut self, stringdelim: char) -> Result<(), String> {
+    /// Returns a error, with its positions wrt the original string, if
+    /// * the string doesnt start with a configured (or the specified) string quote (StringNotFound)
+    /// * the string is not closed (UnterminatedString)
+    /// * there is something after the closing quote (JunkAfterBlockToken)
+    /// * there is a unknown/malformed escape sequence (UnknownEscSeq, ...)
+    ///
+    /// This TStr instance is only trimmed, in case of errors.
+    ///
+    pub fn peel_string(&mut self, stringdelim: Option<&str>) -> Result<Cow<'a, str>, Error> {
         self.trim();
-        let schar = self.char_first().unwrap();
-        let echar = self.char_last().unwrap();
-        if (schar != stringdelim) || (echar != stringdelim) {
-            return Err(format!("TStr:PeelString:{}:Not used to enclose the string fully", stringdelim));
+        let thestr = self.theStr;
+        let mut found: Option<usize> = None;
+        for (sid, quote) in self.cfg.delims.strings.iter().enumerate() {
+            if quote.is_empty() || !thestr.starts_with(quote.as_str()) {
+                continue;
+            }
+            if stringdelim.is_some_and(|sd| sd != quote) {
+                continue;
+            }
+            if found.is_none_or(|fsid| self.cfg.delims.strings[fsid].len() < quote.len()) {
+                found = Some(sid);
+            }
+        }
+        let sid = match found {
+            Some(sid) => sid,
+            None => {
+                let ch = thestr.chars().next().unwrap_or(' ');
+                return Err(self.error_at(ErrorKind::StringNotFound, ch, 0, 0, String::new()));
+            }
+        };
+        let mut string = self.clone();
+        let mut flags = self.cfg.flags.clone();
+        flags.stringquotes_retain = false;
+        flags.string_canbe_asubpart = false;
+        flags.blocktok_dlimuser_endreqd = true;
+        let mut dlimbuf = [0; 4];
+        let space = self.cfg.delims.space.dlim_str(&mut dlimbuf);
+        let tok = string.nexttok_resume(space, true, &mut None, None, None, Some(flags)).unwrap_or(Ok(None))?;
+        let tok = tok.unwrap_or_else(|| string.token_empty());
+        if let Some(ch) = string.char_first() {
+            return Err(string.error_at(ErrorKind::JunkAfterBlockToken, ch, 0, 0, tok.text.into_owned()));
+        }
+        let quote = &self.cfg.delims.strings[sid];
+        let send = (tok.span.end - self.orig_offset()).saturating_sub(quote.len()).max(quote.len());
+        self.theStr = &thestr[quote.len()..send];
+        Ok(tok.text)
+    }
+
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The parts of a bracketed block, returned by TStr::peel_bracket
+pub struct BracketBlock<'a> {
+    /// The textual prefix before the opening bracket, empty if none
+    pub prefix: Cow<'a, str>,
+    /// The index of the bracket type (wrt Delimiters::brackets) of the block
+    pub kind: usize,
+    /// The contents of the block, ie without the opening and closing brackets
+    pub contents: Cow<'a, str>,
+}
+
+impl<'a> BracketBlock<'a> {
+
+    /// Convert into a bracketed block, which doesnt borrow from the original string
+    pub fn into_owned(self) -> BracketBlock<'static> {
+        BracketBlock {
+            prefix: Cow::Owned(self.prefix.into_owned()),
+            kind: self.kind,
+            contents: Cow::Owned(self.contents.into_owned()),
         }
-        self.theStr = &self.theStr[1..self.len()-1];
-        Ok(())
     }
 
 }
@@ -525,10 +1278,18 @@ impl<'a> TStr<'a> {
 /// If one wants to customise the tokenisation characteristics and share it
 /// across multiple instances of TStr, then one could use TStrX to simplify
 /// the same.
+///
+/// The characteristics are compiled into a TConfig, which is shared by all the
+/// TStr's created from this TStrX, as long as the characteristics arent changed.
+/// So the characteristics are changed only through its methods (like flags_mut),
+/// which inturn drop the compiled config.
 pub struct TStrX {
-    pub delims: Delimiters,
+    delims: Delimiters,
     escseqs: HashMap<char, char>,
-    pub flags: Flags,
+    flags: Flags,
+    /// The config compiled from the characteristics, the 1st time it was required,
+    /// after they were last changed
+    cfg: OnceLock<Arc<TConfig>>,
 }
 
 impl TStrX {
@@ -540,6 +1301,7 @@ impl TStrX {
             delims,
             escseqs,
             flags: flags,
+            cfg: OnceLock::new(),
         }
     }
 
@@ -549,9 +1311,58 @@ impl TStrX {
         Self::new_ex(Delimiters::default(), Self::escseqs_default(), Flags::default())
     }
 
-    /// Create an instance of TStr, which inherits/copies the characteristics set wrt this TStrX.
+    /// Create an instance of TStrX, with the same characteristics as the given config.
+    pub fn from_config(cfg: &Arc<TConfig>) -> TStrX {
+        let mut tstrx = Self::new_ex(cfg.delims.clone(), cfg.escseqs.clone(), cfg.flags.clone());
+        tstrx.cfg = OnceLock::from(cfg.clone());
+        tstrx
+    }
+
+    ///
+    /// Return the config corresponding to the current characteristics. It is compiled
+    /// only if the characteristics have changed, since the last time it was required,
+    /// else the same config is shared.
+    ///
+    pub fn config(&self) -> Arc<TConfig> {
+        self.cfg.get_or_init(|| Arc::new(TConfig::new(self.delims.clone(), self.escseqs.clone(), self.flags.clone()))).clone()
+    }
+
+    /// The delimiters
+    pub fn delims(&self) -> &Delimiters {
+        &self.delims
+    }
+
+    /// Allow the delimiters to be changed
+    pub fn delims_mut(&mut self) -> &mut Delimiters {
+        self.cfg.take();
+        &mut self.delims
+    }
+
+    /// The flags
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    /// Allow the flags to be changed
+    pub fn flags_mut(&mut self) -> &mut Flags {
+        self.cfg.take();
+        &mut self.flags
+    }
+
+    /// Create an instance of TStr, which shares the characteristics set wrt this TStrX.
     pub fn from_str<'a>(&self, thestr: &'a str, btrim: bool) -> TStr<'a> {
-        TStr::from_str_ex(thestr, btrim, self.delims.clone(), self.escseqs.clone(), self.flags.clone())
+        TStr::from_str_cfg(thestr, btrim, self.config())
+    }
+
+    /// Create an instance of TString, which shares the characteristics set wrt this TStrX.
+    pub fn from_string(&self, thestr: impl Into<Arc<str>>, btrim: bool) -> TString {
+        TString::from_string_cfg(thestr, btrim, self.config())
+    }
+
+    /// Create an instance of TReader, which shares the characteristics set wrt this TStrX,
+    /// to tokenise the contents of the given reader.
+    pub fn reader<R: std::io::BufRead>(&self, rd: R) -> TReader<R> {
+        TReader::new(rd, self.config())
     }
 
 }
@@ -567,31 +1378,58 @@ impl TStrX {
         escseqs.insert('r', '\r');
         escseqs.insert(delims.escseq, delims.escseq);
         escseqs.insert(delims.space, delims.space);
-        escseqs.insert(delims.string, delims.string);
-        escseqs.insert(delims.bracket.0, delims.bracket.0);
-        escseqs.insert(delims.bracket.1, delims.bracket.1);
+        for string in &delims.strings {
+            Self::escseqs_insert_marker(&mut escseqs, string);
+        }
+        for bracket in &delims.brackets {
+            Self::escseqs_insert_marker(&mut escseqs, &bracket.0);
+            Self::escseqs_insert_marker(&mut escseqs, &bracket.1);
+        }
         escseqs
     }
 
     /// Clear any existing supported escape sequences
     pub fn escseqs_clear(&mut self) {
+        self.cfg.take();
         self.escseqs.clear();
     }
 
     /// Add a new supported escape sequence
     pub fn escseqs_set(&mut self, find: char, replace: char) {
+        self.cfg.take();
         self.escseqs.insert(find, replace);
     }
 
+    /// Setup the 1st char of the given marker, as a escape sequence mapping to itself.
+    /// Escaping the 1st char of a multi char marker, is good enough to stop it from
+    /// being treated as a marker.
+    fn escseqs_insert_marker(escseqs: &mut HashMap<char, char>, marker: &str) {
+        if let Some(ch) = marker.chars().next() {
+            escseqs.insert(ch, ch);
+        }
+    }
+
     /// Sets up the currently configured Space, StringQuote and Bracket chars,
     /// as part of the escape sequencing. This allows the user to escape them
     /// if required as part of string literals, etal.
+    /// In case of multi char markers, its 1st char is setup.
     pub fn escseqs_update(&mut self) {
+        self.cfg.take();
         self.escseqs.insert(self.delims.escseq, self.delims.escseq);
         self.escseqs.insert(self.delims.space, self.delims.space);
-        self.escseqs.insert(self.delims.string, self.delims.string);
-        self.escseqs.insert(self.delims.bracket.0, self.delims.bracket.0);
-        self.escseqs.insert(self.delims.bracket.1, self.delims.bracket.1);
+        for string in &self.delims.strings {
+            Self::escseqs_insert_marker(&mut self.escseqs, string);
+        }
+        for bracket in &self.delims.brackets {
+            Self::escseqs_insert_marker(&mut self.escseqs, &bracket.0);
+            Self::escseqs_insert_marker(&mut self.escseqs, &bracket.1);
+        }
+        for comment in &self.delims.comments_line {
+            Self::escseqs_insert_marker(&mut self.escseqs, comment);
+        }
+        for comment in &self.delims.comments_block {
+            Self::escseqs_insert_marker(&mut self.escseqs, &comment.0);
+        }
     }
 
 }
@@ -650,4 +1488,114 @@ mod tests {
         testlib::test_multibrackets();
     }
 
+    #[test]
+    fn test_errors() {
+        testlib::test_errors();
+    }
+
+    #[test]
+    fn test_token_spans() {
+        testlib::test_token_spans();
+    }
+
+    #[test]
+    fn test_tokens_iter() {
+        testlib::test_tokens_iter();
+    }
+
+    #[test]
+    fn test_tokens_borrow() {
+        testlib::test_tokens_borrow();
+    }
+
+    #[test]
+    fn test_multichar_markers() {
+        testlib::test_multichar_markers();
+    }
+
+    #[test]
+    fn test_multistrings() {
+        testlib::test_multistrings();
+    }
+
+    #[test]
+    fn test_bracket_kinds() {
+        testlib::test_bracket_kinds();
+    }
+
+    #[test]
+    fn test_bracket_strict() {
+        testlib::test_bracket_strict();
+    }
+
+    #[test]
+    fn test_bracket_strings() {
+        testlib::test_bracket_strings();
+    }
+
+    #[test]
+    fn test_comments() {
+        testlib::test_comments();
+    }
+
+    #[test]
+    fn test_reader() {
+        testlib::test_reader();
+    }
+
+    #[test]
+    fn test_escseqs_numeric() {
+        testlib::test_escseqs_numeric();
+    }
+
+    #[test]
+    fn test_quote_join() {
+        testlib::test_quote_join();
+    }
+
+    #[test]
+    fn test_parse_tree() {
+        testlib::test_parse_tree();
+    }
+
+    #[test]
+    fn test_typed() {
+        testlib::test_typed();
+    }
+
+    #[test]
+    fn test_recovery() {
+        testlib::test_recovery();
+    }
+
+    #[test]
+    fn test_diag() {
+        testlib::test_diag();
+    }
+
+    #[test]
+    fn test_peek_rewind() {
+        testlib::test_peek_rewind();
+    }
+
+    #[test]
+    fn test_location() {
+        testlib::test_location();
+    }
+
+    #[test]
+    fn test_cooked_map() {
+        testlib::test_cooked_map();
+    }
+
+    #[test]
+    fn test_tstring() {
+        testlib::test_tstring();
+    }
+
+    #[test]
+    fn test_config_shared() {
+        testlib::test_config_shared();
+    }
+
 }
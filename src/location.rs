@@ -0,0 +1,110 @@
+//!
+//! Location - Map byte offsets in a (multi line) text to line and column
+//! HanishKVC, 2022
+//!
+
+use std::ops::Range;
+
+
+/// The default tab width used wrt the tab expanded column
+pub const TABWIDTH_DEFAULT: usize = 8;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The location of a byte offset in a text. All the members are 1 based.
+pub struct Location {
+    /// The line number
+    pub line: usize,
+    /// The column, counted in bytes from the start of the line
+    pub col_byte: usize,
+    /// The column, counted in chars from the start of the line
+    pub col_char: usize,
+    /// The column, counted in chars, with tabs expanded to the next tab stop
+    pub col_tab: usize,
+}
+
+
+#[derive(Debug, Clone)]
+///
+/// Map byte offsets in a text into their line and columns.
+///
+/// The lines are seperated by \n. The offsets are wrt the text, as is the case wrt the
+/// positions in the errors and the spans in the tokens returned by TStr, when the text
+/// is the original string given to TStr (see TStr::line_map).
+///
+pub struct LineMap<'a> {
+    text: &'a str,
+    /// The byte offset of the starting of each line in text
+    linestarts: Vec<usize>,
+    /// The tab width used wrt the tab expanded column
+    pub tabwidth: usize,
+}
+
+impl<'a> LineMap<'a> {
+
+    /// Create a LineMap for the given text, using the default tab width
+    pub fn new(text: &'a str) -> LineMap<'a> {
+        let mut linestarts = vec![0];
+        linestarts.extend(text.match_indices('\n').map(|(pos, _)| pos + 1));
+        LineMap {
+            text,
+            linestarts,
+            tabwidth: TABWIDTH_DEFAULT,
+        }
+    }
+
+    /// The number of lines in the text
+    pub fn lines(&self) -> usize {
+        self.linestarts.len()
+    }
+
+    /// Return the line (1 based) of the text, without its line ending (\n or \r\n)
+    pub fn line(&self, line: usize) -> Option<&'a str> {
+        let start = *self.linestarts.get(line.checked_sub(1)?)?;
+        let end = self.linestarts.get(line).map(|next| next - 1).unwrap_or(self.text.len());
+        let text = &self.text[start..end];
+        Some(text.strip_suffix('\r').unwrap_or(text))
+    }
+
+    /// Return the byte range of the given line (1 based), without its line ending
+    pub fn line_span(&self, line: usize) -> Option<Range<usize>> {
+        let start = *self.linestarts.get(line.checked_sub(1)?)?;
+        Some(start..start+self.line(line)?.len())
+    }
+
+    ///
+    /// Return the location of the given byte offset. An offset beyond the end of the text
+    /// is treated as the end of the text, and one in the middle of a char as that char.
+    ///
+    pub fn location(&self, pos: usize) -> Location {
+        let mut pos = pos.min(self.text.len());
+        while !self.text.is_char_boundary(pos) {
+            pos -= 1;
+        }
+        let iline = self.linestarts.partition_point(|&start| start <= pos) - 1;
+        let start = self.linestarts[iline];
+        let mut col_char = 1;
+        let mut col_tab = 1;
+        for ch in self.text[start..pos].chars() {
+            col_char += 1;
+            if (ch == '\t') && (self.tabwidth > 0) {
+                col_tab += self.tabwidth - ((col_tab - 1) % self.tabwidth);
+            } else {
+                col_tab += 1;
+            }
+        }
+        Location {
+            line: iline + 1,
+            col_byte: pos - start + 1,
+            col_char,
+            col_tab,
+        }
+    }
+
+    /// Return the locations of the start and end (exclusive) of the given byte range,
+    /// like the span of a token or error.
+    pub fn span(&self, span: &Range<usize>) -> (Location, Location) {
+        (self.location(span.start), self.location(span.end))
+    }
+
+}
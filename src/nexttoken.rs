@@ -3,71 +3,462 @@
 //! HanishKVC, 2022
 //!
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
 
-use crate::{TokenType, Flags, Delimiters};
+use crate::{TokenType, Flags, Delimiters, CookedSeg};
+use crate::error::ErrorKind;
 
 
 enum Phase {
     Begin,
     BtwNormal,
-    BtwString,
-    /// Maintain current open brackets count, as well as the open bracket char
+    /// Maintain the id of the string quote kind, which opened the string
+    BtwString(usize),
+    /// Maintain current open brackets count, as well as the open bracket id
     /// Allow more than one bracket type to be supported.
-    BtwBracket(char, usize),
+    BtwBracket(usize, usize),
     /// Seek delimiter, by dropping any spaces
     EndSeekDelim,
     /// Peek ahead to see and handle any trimmable spaces
     EndCleanup,
+    /// Within a comment, which is being returned as a token
+    BtwComment,
 }
 
 
-pub struct Ctxt {
-    /// The characters of the string, to extract token from
-    pub vchars: Vec<(usize, char)>,
-    /// The initial delimiter specified by user
-    _dlimuser: char,
-    /// The currently active end delimiter
-    cend: char,
+/// A comment being scanned through
+struct Comment {
+    /// The begin and end markers, in case of a block comment
+    block: Option<(String, String)>,
+    /// The current nesting depth, wrt block comments
+    depth: usize,
+    /// If the chars of the comment are part of the token, or else skipped
+    bpush: bool,
+    /// The (char index, byte position, char) which opened the comment
+    beg: (usize, usize, char),
+}
+
+
+///
+/// The state of the scan wrt the token being extracted. It doesnt borrow the string
+/// being tokenised, so that the scan can be suspended and resumed later, once more
+/// text has been appended to the string (see TReader).
+///
+pub struct Scan {
+    /// If the end delimiter is a normal delimiter, ie other than the space delimiter
+    pub bdlimnormal: bool,
     /// The phase of tokenisation
     mphase: Phase,
     /// If we are in escape mode
     pub bescape: bool,
-    /// The token being constructed
-    pub tok: String,
+    /// The token being constructed, once it differs from the underlying source text
+    tok: String,
+    /// If the token has been cooked (escape sequence expanded, quote stripped, ...),
+    /// in which case tok contains the token, else lit maps to it in the source text.
+    bcooked: bool,
+    /// The byte range of the source text, which is the token, till it gets cooked
+    lit: Option<(usize, usize)>,
     /// The current char's index/position
     pub ipos: usize,
     /// The current char's byte position
     pub chpos: usize,
     /// The current char
     pub ch: char,
+    /// The (char index, byte position) of the char, from which the scan continues
+    pub resumepos: (usize, usize),
+    /// The length of the longest marker (delimiter/string/bracket) at the current char, if any
+    pub mklen: usize,
+    /// The byte position till which chars have already been consumed, as part of a marker
+    pub skipto: usize,
     /// The byte position to start searching for next token
     pub nextpos: usize,
-    /// The map of enabled escape sequences
-    esmap: HashMap<char, char>,
     /// Helps control the behaviour of tokenising
     f: Flags,
     /// Possible Token type
     pub toktype: TokenType,
+    /// The (char index, byte position, char) which opened the current string/bracket block
+    pub blockbeg: (usize, usize, char),
+    /// The byte range of the chars, which have contributed to the token
+    pub tokspan: Option<(usize, usize)>,
+    /// The stack of currently open brackets (id, char index, byte position, char),
+    /// maintained only if strict bracket matching is enabled.
+    bstack: Vec<(usize, usize, usize, char)>,
+    /// The string (id, char index, byte position, char) currently open within a bracket block, if any.
+    /// Bracket chars within such strings are not considered wrt bracket balancing.
+    bstring: Option<(usize, usize, usize, char)>,
+    /// The comment currently being scanned through, if any
+    comment: Option<Comment>,
+    /// If a comment was skipped, before the token began
+    bcommentskipped: bool,
+    /// The runs of the token text along with the source text they came from, wrt src,
+    /// maintained only if cooked_map is enabled.
+    pub segs: Vec<CookedSeg>,
 }
 
-impl Ctxt {
 
-    pub fn new(thestr: &str, dlim: char, esmap: HashMap<char, char>, flags: Flags) -> Ctxt {
-        Ctxt {
-            vchars: thestr.char_indices().collect(),
-            _dlimuser: dlim,
-            cend: dlim,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The kind of a marker, noted while scanning a bracket block token
+pub enum MarkKind {
+    /// The begin marker of the given bracket type
+    BracketBegin(usize),
+    /// The end marker of the given bracket type
+    BracketEnd(usize),
+    /// The begin quote of the given string type
+    StringBegin(usize),
+    /// The end quote of the string
+    StringEnd,
+    /// The user specified delimiter
+    Delim,
+}
+
+
+#[derive(Debug, Clone)]
+/// A marker (bracket, quote or delimiter), which was treated as such, while scanning
+/// a bracket block token. The positions are wrt the original string.
+pub struct BracketMark {
+    /// The kind of the marker
+    pub kind: MarkKind,
+    /// The byte position of the marker
+    pub start: usize,
+    /// The byte position just after the marker
+    pub end: usize,
+    /// The length of the token text, before the marker
+    pub toklen: usize,
+}
+
+
+/// The context used to extract a token, ie the string and delimiter, along with the
+/// state of the scan (which is accessed through it).
+pub struct Ctxt<'a, 'c> {
+    /// The string, to extract token from
+    src: &'a str,
+    /// The currently active end delimiter, ie the delimiter specified by user
+    cend: &'c str,
+    /// The map of enabled escape sequences
+    esmap: &'c HashMap<char, char>,
+    /// The markers within bracket blocks, if building a tree of tokens
+    marks: Option<&'c mut Vec<BracketMark>>,
+    /// The byte offset of src wrt the original string, used wrt marks
+    base: usize,
+    /// The state of the scan
+    scan: Scan,
+}
+
+impl<'a, 'c> Deref for Ctxt<'a, 'c> {
+    type Target = Scan;
+
+    fn deref(&self) -> &Scan {
+        &self.scan
+    }
+}
+
+impl<'a, 'c> DerefMut for Ctxt<'a, 'c> {
+    fn deref_mut(&mut self) -> &mut Scan {
+        &mut self.scan
+    }
+}
+
+impl<'a, 'c> Ctxt<'a, 'c> {
+
+    pub fn new(thestr: &'a str, dlim: &'c str, dlimspace: char, esmap: &'c HashMap<char, char>, flags: Flags) -> Ctxt<'a, 'c> {
+        let scan = Scan {
+            bdlimnormal: !((dlim.len() == dlimspace.len_utf8()) && dlim.starts_with(dlimspace)),
             mphase: Phase::Begin,
             bescape: false,
             tok: String::new(),
+            bcooked: false,
+            lit: None,
             ipos: 0,
             chpos: 0,
             ch: ' ',
+            resumepos: (0, 0),
+            mklen: 0,
+            skipto: 0,
             nextpos: 0,
-            esmap: esmap,
             f: flags,
             toktype: TokenType::Unknown,
+            blockbeg: (0, 0, ' '),
+            tokspan: None,
+            bstack: Vec::new(),
+            bstring: None,
+            comment: None,
+            bcommentskipped: false,
+            segs: Vec::new(),
+        };
+        Self::resume(thestr, dlim, esmap, scan)
+    }
+
+    /// Continue the given scan, over the given string, which should be the string
+    /// scanned till now, with possibly more text appended to it.
+    pub fn resume(thestr: &'a str, dlim: &'c str, esmap: &'c HashMap<char, char>, scan: Scan) -> Ctxt<'a, 'c> {
+        Ctxt {
+            src: thestr,
+            cend: dlim,
+            esmap,
+            marks: None,
+            base: 0,
+            scan,
+        }
+    }
+
+    /// Record the markers within bracket blocks into the given vector, to help build a
+    /// tree of tokens, with src being at the given byte offset wrt the original string
+    pub fn marks_record(&mut self, base: usize, marks: &'c mut Vec<BracketMark>) {
+        self.marks = Some(marks);
+        self.base = base;
+    }
+
+    /// Make the token text lengths noted in the recorded markers, relative to the token
+    /// text, after the given number of bytes were trimmed from its start.
+    pub fn marks_trimmed(&mut self, ntrim: usize) {
+        if let Some(marks) = &mut self.marks {
+            for mark in marks.iter_mut() {
+                mark.toklen = mark.toklen.saturating_sub(ntrim);
+            }
+        }
+    }
+
+    /// Record the current marker, if building a tree of tokens
+    fn mark(&mut self, kind: MarkKind) {
+        let (start, toklen) = (self.base + self.chpos, self.tok_len());
+        let end = start + self.unit_len();
+        if let Some(marks) = &mut self.marks {
+            marks.push(BracketMark { kind, start, end, toklen });
+        }
+    }
+
+    /// Suspend the scan, so that it can be resumed later
+    pub fn suspend(self) -> Scan {
+        self.scan
+    }
+
+    /// The byte length of the current unit being processed, ie the current
+    /// marker if any, else the current char.
+    pub fn unit_len(&self) -> usize {
+        if self.mklen > 0 {
+            return self.mklen;
+        }
+        self.ch.len_utf8()
+    }
+
+    /// Check if the given marker is the one found at the current char
+    fn at(&self, marker: &str) -> bool {
+        (self.mklen == marker.len()) && self.src[self.chpos..].starts_with(marker)
+    }
+
+    /// Check if the end delimiter is the given char
+    fn cend_is(&self, ch: char) -> bool {
+        (self.cend.len() == ch.len_utf8()) && self.cend.starts_with(ch)
+    }
+
+    /// Mark the current char/marker as having been consumed as part of the token
+    fn tok_mark(&mut self) {
+        let end = self.chpos + self.unit_len();
+        match self.tokspan {
+            None => self.tokspan = Some((self.chpos, end)),
+            Some((start, _)) => self.tokspan = Some((start, end)),
+        }
+    }
+
+    /// Switch over to building the token in its own string, bcas it no longer
+    /// matches a contiguous part of the source text.
+    fn tok_cook(&mut self) {
+        if self.bcooked {
+            return;
+        }
+        if let Some((start, end)) = self.lit {
+            let src = self.src;
+            self.tok.push_str(&src[start..end]);
+        }
+        self.bcooked = true;
+    }
+
+    /// The length in bytes of the token built till now
+    fn tok_len(&self) -> usize {
+        if self.bcooked {
+            return self.tok.len();
+        }
+        self.lit.map(|(start, end)| end - start).unwrap_or(0)
+    }
+
+    /// Map the given length of token text, just added to it, to the given range of source text.
+    /// A literal run is merged into the previous one, if both are contiguous.
+    fn seg_add(&mut self, clen: usize, raw: (usize, usize)) {
+        if !self.f.cooked_map {
+            return;
+        }
+        let cend = self.tok_len();
+        let cstart = cend - clen;
+        if let Some(last) = self.segs.last_mut() {
+            if last.is_literal() && (clen == raw.1 - raw.0) && (last.cooked.end == cstart) && (last.raw.end == raw.0) {
+                last.cooked.end = cend;
+                last.raw.end = raw.1;
+                return;
+            }
+        }
+        self.segs.push(CookedSeg { cooked: cstart..cend, raw: raw.0..raw.1 });
+    }
+
+    /// Add the current char/marker to the token
+    fn tok_push(&mut self) {
+        let end = self.chpos + self.unit_len();
+        if !self.bcooked {
+            match self.lit {
+                None => self.lit = Some((self.chpos, end)),
+                Some((start, lend)) if lend == self.chpos => self.lit = Some((start, end)),
+                Some(_) => self.tok_cook(),
+            }
+        }
+        if self.bcooked {
+            let (src, chpos) = (self.src, self.chpos);
+            self.tok.push_str(&src[chpos..end]);
+        }
+        self.seg_add(end - self.chpos, (self.chpos, end));
+        self.tok_mark();
+    }
+
+    /// Add the given char to the token, in place of the source text starting at rawstart
+    /// till the end of the current char/marker.
+    fn tok_push_ch(&mut self, ch: char, rawstart: usize) {
+        self.tok_cook();
+        self.tok.push(ch);
+        self.seg_add(ch.len_utf8(), (rawstart, self.chpos + self.unit_len()));
+        self.tok_mark();
+    }
+
+    /// Take out the token built till now.
+    /// It borrows from the source text, if the token hasnt been cooked.
+    pub fn tok_take(&mut self) -> Cow<'a, str> {
+        if self.bcooked {
+            return Cow::Owned(std::mem::take(&mut self.tok));
+        }
+        match self.lit {
+            Some((start, end)) => Cow::Borrowed(&self.src[start..end]),
+            None => Cow::Borrowed(""),
+        }
+    }
+
+    ///
+    /// Check if a numeric escape sequence (of the enabled families) starts at the current char,
+    /// ie the char following the escape char. If so return the char represented by it (or the
+    /// error wrt it), along with the length in bytes of the numeric escape sequence.
+    ///
+    /// * hex: xHH or x{H..} (1 to 6 hex digits)
+    /// * unicode: uHHHH or u{H..} (1 to 6 hex digits)
+    /// * octal: O, OO or OOO (with a value upto 377)
+    ///
+    fn escseq_numeric(&self) -> Option<(Result<char, ErrorKind>, usize)> {
+        let s = &self.src[self.chpos..];
+        let (radix, fixed) = match self.ch {
+            'x' if self.f.escapesequences_hex => (16, 2),
+            'u' if self.f.escapesequences_unicode => (16, 4),
+            '0'..='7' if self.f.escapesequences_octal => (8, 0),
+            _ => return None,
+        };
+        let digits = |from: usize, max: usize| -> &str {
+            let cnt = s[from..].chars().take(max).take_while(|c| c.is_digit(radix)).count();
+            &s[from..from+cnt]
+        };
+        let (sdigits, len) = if radix == 8 {
+            let sdigits = digits(0, 3);
+            (sdigits, sdigits.len())
+        } else if s[1..].starts_with('{') {
+            let sdigits = digits(2, 7);
+            let len = 2 + sdigits.len();
+            if sdigits.is_empty() || (sdigits.len() > 6) || !s[len..].starts_with('}') {
+                return Some((Err(ErrorKind::MalformedEscSeq), len));
+            }
+            (sdigits, len + 1)
+        } else {
+            let sdigits = digits(1, fixed);
+            if sdigits.len() != fixed {
+                return Some((Err(ErrorKind::MalformedEscSeq), 1 + sdigits.len()));
+            }
+            (sdigits, 1 + fixed)
+        };
+        let value = u32::from_str_radix(sdigits, radix).unwrap();
+        if (radix == 8) && (value > 0o377) {
+            return Some((Err(ErrorKind::InvalidCodePoint), len));
+        }
+        match char::from_u32(value) {
+            Some(ch) => Some((Ok(ch), len)),
+            None => Some((Err(ErrorKind::InvalidCodePoint), len)),
+        }
+    }
+
+    /// Remember the current char as the one which opened a string/bracket block
+    fn block_mark(&mut self) {
+        self.blockbeg = (self.ipos, self.chpos, self.ch);
+    }
+
+    /// Push the current bracket onto the open brackets stack, if strict bracket matching
+    fn bstack_push(&mut self, bid: usize) {
+        if self.f.bracket_strict {
+            let open = (bid, self.ipos, self.chpos, self.ch);
+            self.bstack.push(open);
+        }
+    }
+
+    /// Start scanning through a comment, which begins at the current char.
+    /// If bpush, the comment is added to the token, else it is skipped.
+    fn comment_begin(&mut self, block: Option<(&str, &str)>, bpush: bool) {
+        self.comment = Some(Comment {
+            block: block.map(|(bchk, echk)| (bchk.to_string(), echk.to_string())),
+            depth: 1,
+            bpush,
+            beg: (self.ipos, self.chpos, self.ch),
+        });
+        if bpush {
+            self.tok_push();
+        }
+    }
+
+}
+
+impl Scan {
+
+    /// If the token is complete, with only the spaces (or delimiter) following it being
+    /// consumed, when the scanning ended.
+    pub fn ended(&self) -> bool {
+        matches!(self.mphase, Phase::EndCleanup)
+    }
+
+    /// If the scanning ended in a unterminated block comment, which started after the
+    /// token was already complete, return the byte position of the comment, so that
+    /// the token can be returned now and the comment reported wrt the next token.
+    pub fn comment_deferred(&self) -> Option<usize> {
+        if let (Some(Comment { block: Some(_), beg, .. }), Phase::EndCleanup) = (&self.comment, &self.mphase) {
+            return Some(beg.1);
+        }
+        None
+    }
+
+    /// If the scanning ended without finding a token, with only skipped comments (and
+    /// spaces) having been found.
+    pub fn comment_only(&self) -> bool {
+        matches!(self.mphase, Phase::Begin) && self.bcommentskipped && self.tokspan.is_none()
+    }
+
+    /// If the scanning ended in the middle of a string/bracket block (or block comment),
+    /// return the corresponding kind of error, along with the (char index,
+    /// byte position, char) which opened the unterminated block.
+    pub fn unterminated(&self) -> Option<(ErrorKind, (usize, usize, char))> {
+        if let Some(Comment { block: Some(_), beg, .. }) = &self.comment {
+            if self.comment_deferred().is_none() {
+                return Some((ErrorKind::UnterminatedComment, *beg));
+            }
+        }
+        match self.mphase {
+            Phase::BtwString(_) => Some((ErrorKind::UnterminatedString, self.blockbeg)),
+            Phase::BtwBracket(_,_) => {
+                if let Some((_, ipos, chpos, ch)) = self.bstring {
+                    return Some((ErrorKind::UnterminatedString, (ipos, chpos, ch)));
+                }
+                Some((ErrorKind::UnterminatedBracket, self.blockbeg))
+            }
+            _ => None,
         }
     }
 
@@ -96,48 +487,173 @@ pub enum CharType {
     /// Additionally it can also act as a delimiter to terminate/demarcate
     /// a token, if cend in Ctxt set to this char.
     DelimSpace(char),
-    /// A delimiter of tokens in general
-    DelimNormal(char),
+    /// A delimiter of tokens in general, can be made up of one or more chars.
+    /// It is the end delimiter in Ctxt (ie the one specified by the user),
+    /// if it is other than the space delimiter.
+    DelimNormal,
     /// Identify a block of chars including spaces (or other normal demarcaters),
     /// which will be treated has a single token, which is demarcated by this
-    /// same char at both ends.
-    DelimString(char),
+    /// same marker (one or more chars) at both ends.
+    /// The 1st member is the id of the string quote kind. A string opened by
+    /// a given kind can be closed only by the same kind.
+    DelimString(usize, String),
     /// Identify a block of chars including spaces (or other normal demarcaters),
     /// which will be treated has a single token, which is demarcated by this
-    /// set of chars at either end.
+    /// set of begin and end markers (one or more chars each) at either end.
     /// It allows one such block to contain additional such blocks within it,
     /// and so on for what ever depth required.
-    DelimBracket(char, char),
+    /// The 1st member is the id of the bracket type.
+    DelimBracket(usize, String, String),
+    /// Consumes the chars of the comment currently being scanned through, if any,
+    /// so that it needs to be the 1st in the chain.
+    InComment,
+    /// The marker (one or more chars) which begins a comment till the end of the line.
+    CommentLine(String),
+    /// The begin and end markers (one or more chars each) of a block comment.
+    CommentBlock(String, String),
     /// Represents all the other chars, which inturn will be treated as normal chars.
     Normal,
 }
 
 impl CharType {
 
+    ///
+    /// Handle the begining of a comment at the current char. Comments are recognised
+    /// only outside strings (including strings within bracket blocks).
+    ///
+    /// Comments within bracket blocks are retained as is, as part of the block token,
+    /// with any brackets or quotes within them being ignored.
+    ///
+    /// Outside bracket blocks, if comments_retain flag is set, the comment is returned
+    /// as a token of its own, else it is skipped. A line comment also terminates the
+    /// token being currently built, while a block comment is skipped like it was not there.
+    ///
+    fn comment_start(x: &mut Ctxt, block: Option<(&str, &str)>) -> Action {
+        match x.mphase {
+            Phase::BtwString(_) | Phase::BtwComment => {
+                Action::ContinueChain
+            }
+            Phase::BtwBracket(_,_) => {
+                if x.bstring.is_some() {
+                    return Action::ContinueChain;
+                }
+                x.comment_begin(block, true);
+                Action::NextChar
+            }
+            Phase::Begin => {
+                if x.f.comments_retain {
+                    x.toktype = TokenType::Comment;
+                    x.mphase = Phase::BtwComment;
+                    x.comment_begin(block, true);
+                } else {
+                    x.bcommentskipped = true;
+                    x.comment_begin(block, false);
+                }
+                Action::NextChar
+            }
+            _ if x.f.comments_retain => {
+                // The comment will be returned as the next token
+                x.nextpos = x.chpos;
+                x.mphase = Phase::EndCleanup;
+                Action::DoneBreak
+            }
+            Phase::BtwNormal | Phase::EndSeekDelim => {
+                if block.is_none() {
+                    x.nextpos = x.chpos;
+                    x.mphase = Phase::EndCleanup;
+                }
+                x.comment_begin(block, false);
+                Action::NextChar
+            }
+            Phase::EndCleanup => {
+                x.comment_begin(block, false);
+                Action::NextChar
+            }
+        }
+    }
+
     ///
     /// If Multiple DelimBracket sets are provided, then the 1st begining bracket type
     /// matched (ie outermost bracket type) will be used for bracket block delimiting /
     /// demarcating wrt that token, including any included sub bracket blocks
     /// (ie brackets within brackets) within that block token.
     ///
+    /// However if bracket_strict flag is set, all bracket types are tracked using a
+    /// stack, and each closing bracket should match the last opened bracket. The block
+    /// token ends, only when all the brackets opened within it are closed.
+    ///
+    /// Multi char markers are matched only if they are the longest marker found at
+    /// the current char (Ctxt.mklen), and inturn are consumed as a single unit.
+    ///
     /// DevNote: nextpos contains the position of last char to consume wrt current tok,
     /// till the logic executes a Action::DoneBreak from within Phase::EndCleanup,
     /// at which point it points to the begining of the next token roughly.
     ///
-    pub fn process_char(&self, x: &mut Ctxt) -> Result<Action, String> {
+    pub fn process_char(&self, x: &mut Ctxt) -> Result<Action, ErrorKind> {
         //print!("DBUG:NextToken:ProcessChar:{}:{}\n", x.ipos, x.ch);
-        match *self {
+        match self {
+            CharType::InComment => {
+                let mut comment = match x.comment.take() {
+                    Some(comment) => comment,
+                    None => return Ok(Action::ContinueChain),
+                };
+                let mut bend = false;
+                match &comment.block {
+                    None => {
+                        if x.ch == '\n' {
+                            // The newline is not part of the line comment
+                            if let Phase::BtwComment = x.mphase {
+                                x.nextpos = x.chpos;
+                                x.mphase = Phase::EndCleanup;
+                            }
+                            return Ok(Action::ContinueChain);
+                        }
+                    }
+                    Some((bchk, echk)) => {
+                        if x.at(echk) {
+                            comment.depth -= 1;
+                            bend = comment.depth == 0;
+                        } else if x.f.comment_block_nestable && x.at(bchk) {
+                            comment.depth += 1;
+                        }
+                    }
+                }
+                if comment.bpush {
+                    x.tok_push();
+                }
+                if !bend {
+                    x.comment = Some(comment);
+                } else if let Phase::BtwComment = x.mphase {
+                    x.nextpos = x.chpos;
+                    x.mphase = Phase::EndCleanup;
+                }
+                return Ok(Action::NextChar);
+            }
+            CharType::CommentLine(chk) => {
+                if !x.at(chk) {
+                    return Ok(Action::ContinueChain);
+                }
+                return Ok(Self::comment_start(x, None));
+            }
+            CharType::CommentBlock(bchk, echk) => {
+                if !x.at(bchk) {
+                    return Ok(Action::ContinueChain);
+                }
+                return Ok(Self::comment_start(x, Some((bchk, echk))));
+            }
             CharType::EscSeq(chk) => {
+                let chk = *chk;
                 if !x.bescape {
                     if x.ch != chk {
                         return Ok(Action::ContinueChain);
                     }
                     match x.mphase {
-                        Phase::BtwNormal | Phase::BtwString | Phase::BtwBracket(_,_) => {
+                        Phase::BtwNormal | Phase::BtwString(_) | Phase::BtwBracket(_,_) => {
                             if x.f.escapesequences_expand {
                                 x.bescape = true;
+                                x.tok_mark();
                             } else {
-                                x.tok.push(x.ch);
+                                x.tok_push();
                             }
                             return Ok(Action::NextChar);
                         }
@@ -148,18 +664,24 @@ impl CharType {
                 }
                 x.bescape = false;
                 if !x.f.escapesequences_expand {
-                    x.tok.push(x.ch);
+                    x.tok_push();
                 } else {
+                    if let Some((gotr, len)) = x.escseq_numeric() {
+                        x.mklen = len;
+                        x.tok_push_ch(gotr?, x.chpos - chk.len_utf8());
+                        return Ok(Action::NextChar);
+                    }
                     let replace = x.esmap.get(&x.ch);
                     if replace.is_none() {
-                        return Err(format!("CharType:ProcessChar:Unknown escseq [{}] @ {}", x.ch, x.ipos));
+                        return Err(ErrorKind::UnknownEscSeq);
                     }
-                    x.tok.push(*replace.unwrap());
+                    x.tok_push_ch(*replace.unwrap(), x.chpos - chk.len_utf8());
                 }
                 return Ok(Action::NextChar);
             }
             CharType::DelimSpace(chk) => {
-                if x.ch != chk {
+                let chk = *chk;
+                if (x.ch != chk) || (x.mklen > chk.len_utf8()) {
                     return Ok(Action::ContinueChain);
                 }
                 match x.mphase {
@@ -167,33 +689,37 @@ impl CharType {
                         if x.f.trim {
                             return Ok(Action::NextChar);
                         }
-                        x.tok.push(x.ch);
+                        x.tok_push();
                         return Ok(Action::NextChar);
                     }
                     Phase::BtwNormal => {
-                        if chk == x.cend {
+                        if x.cend_is(chk) {
                             x.nextpos = x.chpos;
                             x.mphase = Phase::EndCleanup;
                             return Ok(Action::NextChar);
                         }
-                        x.tok.push(x.ch);
+                        x.tok_push();
                         return Ok(Action::NextChar);
                     }
-                    Phase::BtwString | Phase::BtwBracket(_,_) => {
+                    Phase::BtwString(_) | Phase::BtwBracket(_,_) | Phase::BtwComment => {
+                        // Note the delimiters within bracket blocks, wrt a tree of tokens
+                        if matches!(x.mphase, Phase::BtwBracket(_,_)) && x.bstring.is_none() && x.cend_is(chk) {
+                            x.mark(MarkKind::Delim);
+                        }
                         // NOTE: For now not worrying about delim space within string or bracket token needing to be escaped.
                         // However from a overall flow perspective, the delim needs to be escaped, to ensure proper functioning
                         // of the overall logic.
-                        x.tok.push(x.ch);
+                        x.tok_push();
                         return Ok(Action::NextChar);
                     }
                     Phase::EndSeekDelim => {
                         x.nextpos = x.chpos;
-                        if chk == x.cend {
+                        if x.cend_is(chk) {
                             x.mphase = Phase::EndCleanup;
                             return Ok(Action::NextChar);
                         }
                         if !x.f.trim {
-                            x.tok.push(x.ch);
+                            x.tok_push();
                         }
                         return Ok(Action::NextChar);
                     }
@@ -206,66 +732,90 @@ impl CharType {
                     }
                 }
             },
-            CharType::DelimNormal(chk) => {
-                if x.ch != chk {
+            CharType::DelimNormal => {
+                if !x.bdlimnormal || !x.at(x.cend) {
                     return Ok(Action::ContinueChain);
                 }
                 match x.mphase {
-                    Phase::BtwString | Phase::BtwBracket(_,_) => {
+                    Phase::BtwString(_) | Phase::BtwBracket(_,_) => {
+                        // Note the delimiters within bracket blocks, wrt a tree of tokens
+                        if matches!(x.mphase, Phase::BtwBracket(_,_)) && x.bstring.is_none() {
+                            x.mark(MarkKind::Delim);
+                        }
                         // NOTE: For now not worrying about delim normal within string or bracket token needing to be escaped.
                         // However from a overall flow perspective, the delim needs to be escaped, to ensure proper functioning
                         // of the overall logic.
-                        x.tok.push(x.ch);
+                        x.tok_push();
                         return Ok(Action::NextChar);
                     }
                     Phase::EndSeekDelim => {
                         x.nextpos = x.chpos;
-                        if chk == x.cend {
-                            x.mphase = Phase::EndCleanup;
-                            return Ok(Action::NextChar);
-                        }
-                        return Err(format!("DBUG:CharType:DelimNormal:ProcessChar:EndSeekingDeLim:Non delim char [{}] @ {}", x.ch, x.ipos));
+                        x.mphase = Phase::EndCleanup;
+                        return Ok(Action::NextChar);
                     }
                     Phase::EndCleanup => {
                         x.nextpos = x.chpos;
                         return Ok(Action::DoneBreak);
                     }
                     _ => {
-                        if chk == x.cend {
-                            x.nextpos = x.chpos;
-                            x.mphase = Phase::EndCleanup;
-                            return Ok(Action::NextChar);
-                        }
-                        x.tok.push(x.ch);
+                        x.nextpos = x.chpos;
+                        x.mphase = Phase::EndCleanup;
                         return Ok(Action::NextChar);
                     }
                 }
             }
-            CharType::DelimString(chk) => {
-                if x.ch != chk {
+            CharType::DelimString(sid, chk) => {
+                let sid = *sid;
+                if !x.at(chk) {
                     return Ok(Action::ContinueChain);
                 }
                 match x.mphase {
                     Phase::Begin => {
-                        x.toktype = TokenType::String;
-                        x.mphase = Phase::BtwString;
+                        x.toktype = TokenType::String(sid);
+                        x.mphase = Phase::BtwString(sid);
+                        x.block_mark();
                         if x.f.stringquotes_retain {
-                            x.tok.push(x.ch);
+                            x.tok_push();
+                        } else {
+                            x.tok_mark();
                         }
                         return Ok(Action::NextChar);
                     }
                     Phase::BtwNormal => {
                         if x.f.string_canbe_asubpart {
-                            x.toktype = TokenType::String; // Maybe add a StringPlus type
-                            x.mphase = Phase::BtwString;
+                            x.toktype = TokenType::String(sid); // Maybe add a StringPlus type
+                            x.mphase = Phase::BtwString(sid);
+                            x.block_mark();
                         }
                         // In this case dont bother about StringQuotesRetain flag,
                         // bcas its definitely in the middle of some other token
                         // So retain the quote.
-                        x.tok.push(x.ch);
+                        x.tok_push();
+                        return Ok(Action::NextChar);
+                    }
+                    Phase::BtwString(curs) if curs != sid => {
+                        // A different kind of string quote, within a string
+                        x.tok_push();
+                        return Ok(Action::NextChar);
+                    }
+                    Phase::BtwBracket(_,_) => {
+                        // Track strings within bracket blocks, so that brackets within them
+                        // can be ignored wrt bracket balancing.
+                        match x.bstring {
+                            None => {
+                                x.bstring = Some((sid, x.ipos, x.chpos, x.ch));
+                                x.mark(MarkKind::StringBegin(sid));
+                            }
+                            Some((curs, _, _, _)) if curs == sid => {
+                                x.bstring = None;
+                                x.mark(MarkKind::StringEnd);
+                            }
+                            Some(_) => (),
+                        }
+                        x.tok_push();
                         return Ok(Action::NextChar);
                     }
-                    Phase::BtwString => {
+                    Phase::BtwString(_) => {
                         if x.f.blocktok_dlimuser_endreqd {
                             x.mphase = Phase::EndSeekDelim;
                         } else if x.f.string_canbe_asubpart {
@@ -275,84 +825,112 @@ impl CharType {
                         }
                         x.nextpos = x.chpos;
                         if x.f.stringquotes_retain {
-                            x.tok.push(x.ch);
+                            x.tok_push();
+                        } else {
+                            x.tok_mark();
                         }
                         return Ok(Action::NextChar);
                     }
                     Phase::EndSeekDelim => {
                         x.nextpos = x.chpos;
-                        return Err(format!("DBUG:CharType:DelimString:ProcessChar:EndSeekingDeLim:Non delim char [{}] @ {}", x.ch, x.ipos));
+                        return Err(ErrorKind::JunkAfterBlockToken);
                     }
                     Phase::EndCleanup => {
                         x.nextpos = x.chpos;
                         return Ok(Action::DoneBreak);
                     }
                     _ => {
-                        x.tok.push(x.ch);
+                        x.tok_push();
                         return Ok(Action::NextChar);
                     }
                 }
             },
-            CharType::DelimBracket(bchk, echk) => {
-                if x.ch == bchk {
+            CharType::DelimBracket(bid, bchk, echk) => {
+                let bid = *bid;
+                if x.bstring.is_some() && (x.at(bchk) || x.at(echk)) {
+                    // A bracket within a string within a bracket block
+                    x.tok_push();
+                    return Ok(Action::NextChar);
+                }
+                if x.at(bchk) {
                     match x.mphase {
                         Phase::Begin => {
                             if !x.f.mainbracket_beginstandalone {
-                                return Err(format!("CharType:ProcessChar:Opening bracket [{}] @ {} at begining of token???", bchk, x.ipos));
+                                return Err(ErrorKind::BracketNotAllowedAtStart);
                             }
                             x.toktype = TokenType::BracketStandalone;
-                            x.mphase = Phase::BtwBracket(bchk, 1);
-                            x.tok.push(x.ch);
+                            x.mphase = Phase::BtwBracket(bid, 1);
+                            x.block_mark();
+                            x.bstack_push(bid);
+                            x.mark(MarkKind::BracketBegin(bid));
+                            x.tok_push();
                             return Ok(Action::NextChar);
                         }
                         Phase::BtwNormal => {
                             if !x.f.mainbracket_beginprefixed {
-                                return Err(format!("CharType:ProcessChar:Opening bracket [{}] @ {} not at begining of token???", bchk, x.ipos));
+                                return Err(ErrorKind::BracketNotAllowedInMiddle);
                             }
                             x.toktype = TokenType::BracketPrefixed;
-                            x.mphase = Phase::BtwBracket(bchk, 1);
-                            x.tok.push(x.ch);
+                            x.mphase = Phase::BtwBracket(bid, 1);
+                            x.block_mark();
+                            x.bstack_push(bid);
+                            x.mark(MarkKind::BracketBegin(bid));
+                            x.tok_push();
                             return Ok(Action::NextChar);
                         }
-                        Phase::BtwString => {
-                            x.tok.push(x.ch);
+                        Phase::BtwString(_) | Phase::BtwComment => {
+                            x.tok_push();
                             return Ok(Action::NextChar);
                         }
                         Phase::BtwBracket(curb, cnt) => {
-                            if curb == bchk {
+                            if x.f.bracket_strict {
+                                x.bstack_push(bid);
+                                x.mphase = Phase::BtwBracket(curb, cnt+1);
+                            } else if curb == bid {
                                 x.mphase = Phase::BtwBracket(curb, cnt+1);
                             }
-                            x.tok.push(x.ch);
+                            // Other types of brackets are marked even if not tracked (ie non strict),
+                            // so that they can be treated as blocks in a tree, if they are balanced.
+                            x.mark(MarkKind::BracketBegin(bid));
+                            x.tok_push();
                             return Ok(Action::NextChar);
                         }
                         Phase::EndSeekDelim => {
                             x.nextpos = x.chpos;
-                            return Err(format!("DBUG:CharType:DelimBracket:ProcessChar:EndSeekingDeLim:Non delim char [{}] @ {}", x.ch, x.ipos));
+                            return Err(ErrorKind::JunkAfterBlockToken);
                         }
                         Phase::EndCleanup => {
                             x.nextpos = x.chpos;
                             return Ok(Action::DoneBreak);
                         }
                     }
-                } else if x.ch == echk {
+                } else if x.at(echk) {
                     match x.mphase {
                         Phase::Begin => {
-                            return Err(format!("CharType:ProcessChar:Closing bracket [{}] @ {} at begining of token???", echk, x.ipos));
+                            return Err(ErrorKind::UnbalancedClosingBracket);
                         }
                         Phase::BtwNormal => {
-                            return Err(format!("CharType:ProcessChar:Closing bracket [{}] @ {} at middle of normal token???", echk, x.ipos));
+                            return Err(ErrorKind::UnbalancedClosingBracket);
                         }
-                        Phase::BtwString => {
-                            x.tok.push(x.ch);
+                        Phase::BtwString(_) | Phase::BtwComment => {
+                            x.tok_push();
                             return Ok(Action::NextChar);
                         }
                         Phase::BtwBracket(curb, cnt) => {
-                            if curb != bchk {
-                                x.tok.push(x.ch);
+                            if x.f.bracket_strict {
+                                let (obid, oipos, ochpos, och) = x.bstack.pop().unwrap();
+                                if obid != bid {
+                                    x.blockbeg = (oipos, ochpos, och);
+                                    return Err(ErrorKind::MismatchedBracket);
+                                }
+                            } else if curb != bid {
+                                x.mark(MarkKind::BracketEnd(bid));
+                                x.tok_push();
                                 return Ok(Action::NextChar);
                             }
                             let cnt = cnt - 1;
-                            x.tok.push(x.ch);
+                            x.mark(MarkKind::BracketEnd(bid));
+                            x.tok_push();
                             if cnt == 0 {
                                 x.nextpos = x.chpos;
                                 if x.f.blocktok_dlimuser_endreqd {
@@ -367,7 +945,7 @@ impl CharType {
                         }
                         Phase::EndSeekDelim => {
                             x.nextpos = x.chpos;
-                            return Err(format!("DBUG:CharType:DelimBracket:ProcessChar:EndSeekingDeLim:Non delim char [{}] @ {}", x.ch, x.ipos));
+                            return Err(ErrorKind::JunkAfterBlockToken);
                         }
                         Phase::EndCleanup => {
                             x.nextpos = x.chpos;
@@ -386,7 +964,7 @@ impl CharType {
                     }
                     Phase::EndSeekDelim => {
                         x.nextpos = x.chpos;
-                        return Err(format!("DBUG:CharType:Normal:ProcessChar:EndSeekingDeLim:Non delim char [{}] @ {}", x.ch, x.ipos));
+                        return Err(ErrorKind::JunkAfterBlockToken);
                     }
                     Phase::EndCleanup => {
                         x.nextpos = x.chpos;
@@ -394,7 +972,7 @@ impl CharType {
                     }
                     _ => ()
                 }
-                x.tok.push(x.ch);
+                x.tok_push();
                 return Ok(Action::NextChar);
             },
         }
@@ -403,43 +981,224 @@ impl CharType {
 }
 
 
+#[derive(Debug, Default)]
+/// The chartypes to try (in order), wrt a class of chars
+pub struct CharClass {
+    /// The indices of the chartypes (wrt VCharTypes::vct)
+    pub vct: Vec<usize>,
+    /// The indices of the chartypes, including DelimNormal, for use if the
+    /// char is the 1st char of the normal delimiter
+    pub vctdn: Vec<usize>,
+    /// If any of the markers start with the chars in this class
+    pub bmarker: bool,
+}
+
+
+#[derive(Debug)]
 /// The vector of chartypes, which will be used by nexttok, to process
 /// the chars in the string it is given, to identify the next token
 pub struct VCharTypes {
     pub vct: Vec<CharType>,
+    /// All the multi/single char markers used by the chartypes, longest first
+    markers: Vec<String>,
+    /// The class of each ascii char
+    ascii: [u16; 128],
+    /// The class of the non ascii chars, which start some marker
+    nonascii: HashMap<char, u16>,
+    /// The classes of chars, with class 0 being the chars which dont start any marker
+    classes: Vec<CharClass>,
+    /// The chartypes to try, when in escape mode
+    pub vescape: Vec<usize>,
 }
 
 impl VCharTypes {
 
     ///
-    /// Create the vector of chartypes, from the given chars
+    /// Create the vector of chartypes, from the given chars/markers.
     ///
-    /// normaldelim: the normal delimiter, if seperate from the space delimiter
+    /// It doesnt depend on the normal delimiter specified by the user (wrt a given
+    /// token), so that it can be created once and reused across tokens.
     ///
-    pub fn from_chars(delimescseq: char, delimspace: char, delimstring: char, delimbracket: (char,char), normaldelim: Option<char>, odelimbracket: Option<(char,char)>) -> VCharTypes {
+    pub fn from_chars(delimescseq: char, delimspace: char, delimstrings: &[String], delimbrackets: &[(String, String)]) -> VCharTypes {
         let mut vct = Vec::new();
+        let mut markers = Vec::new();
         vct.push(CharType::EscSeq(delimescseq));
-        if normaldelim.is_some() {
-            let delim = normaldelim.unwrap();
-            if delim !=  delimspace {
-                vct.push(CharType::DelimNormal(delim));
-            }
-        }
+        vct.push(CharType::DelimNormal);
         vct.push(CharType::DelimSpace(delimspace));
-        vct.push(CharType::DelimString(delimstring));
-        vct.push(CharType::DelimBracket(delimbracket.0, delimbracket.1));
-        if odelimbracket.is_some() {
-            let obracket = odelimbracket.unwrap();
-            vct.push(CharType::DelimBracket(obracket.0, obracket.1));
+        for (sid, delimstring) in delimstrings.iter().enumerate() {
+            vct.push(CharType::DelimString(sid, delimstring.to_string()));
+            markers.push(delimstring.to_string());
+        }
+        for (bid, delimbracket) in delimbrackets.iter().enumerate() {
+            vct.push(CharType::DelimBracket(bid, delimbracket.0.to_string(), delimbracket.1.to_string()));
+            markers.push(delimbracket.0.to_string());
+            markers.push(delimbracket.1.to_string());
         }
         vct.push(CharType::Normal);
+        markers.retain(|m| !m.is_empty());
+        markers.sort_by_key(|m| std::cmp::Reverse(m.len()));
         VCharTypes {
-            vct: vct
+            vct,
+            markers,
+            ascii: [0; 128],
+            nonascii: HashMap::new(),
+            classes: Vec::new(),
+            vescape: Vec::new(),
         }
     }
 
-    pub fn from_delimiters(delims: &Delimiters, normaldelim: Option<char>) -> VCharTypes {
-        return Self::from_chars(delims.escseq, delims.space, delims.string, delims.bracket, normaldelim, delims.obracket);
+    pub fn from_delimiters(delims: &Delimiters) -> VCharTypes {
+        let mut vcts = Self::from_chars(delims.escseq, delims.space, &delims.strings, &delims.brackets);
+        vcts.add_comments(&delims.comments_line, &delims.comments_block);
+        vcts.compile();
+        vcts
+    }
+
+    ///
+    /// Create the vector of chartypes, such that every char is processed through the
+    /// full chain of chartypes (with the markers looked for at every char), ie like
+    /// before the chartypes were compiled into per char class lookups.
+    ///
+    /// Useful as a baseline, when measuring the tokenising throughput.
+    ///
+    #[cfg(feature = "bench")]
+    pub fn from_delimiters_uncompiled(delims: &Delimiters) -> VCharTypes {
+        let mut vcts = Self::from_chars(delims.escseq, delims.space, &delims.strings, &delims.brackets);
+        vcts.add_comments(&delims.comments_line, &delims.comments_block);
+        let all: Vec<usize> = (0..vcts.vct.len()).collect();
+        vcts.classes = vec![CharClass { vct: all.clone(), vctdn: all.clone(), bmarker: true }];
+        vcts.vescape = all;
+        vcts
+    }
+
+    ///
+    /// Return the chars, one of which the current char should be, for the given chartype
+    /// to act on it. None if it can act on any char.
+    ///
+    /// NOTE: DelimNormal depends on the delimiter specified by the user wrt each token,
+    /// so it is handled seperately.
+    ///
+    fn triggers(ct: &CharType) -> Option<Vec<char>> {
+        let first = |m: &str| m.chars().next();
+        let vm: Vec<Option<char>> = match ct {
+            CharType::InComment | CharType::Normal | CharType::DelimNormal => return None,
+            CharType::EscSeq(chk) | CharType::DelimSpace(chk) => vec![Some(*chk)],
+            CharType::CommentLine(chk) | CharType::DelimString(_, chk) => vec![first(chk)],
+            CharType::CommentBlock(bchk, _) => vec![first(bchk)],
+            CharType::DelimBracket(_, bchk, echk) => vec![first(bchk), first(echk)],
+        };
+        // A empty marker matches at any char
+        vm.into_iter().collect()
+    }
+
+    ///
+    /// Compile the chartypes into a lookup of the chartypes to try wrt each char, so that
+    /// only those chartypes, which can act on a given char, need to be tried wrt it.
+    ///
+    fn compile(&mut self) {
+        let vtriggers: Vec<Option<Vec<char>>> = self.vct.iter().map(Self::triggers).collect();
+        let mut vchars: Vec<char> = vtriggers.iter().flatten().flatten().copied().collect();
+        let vmchars: Vec<char> = self.markers.iter().filter_map(|m| m.chars().next()).collect();
+        vchars.extend(&vmchars);
+        vchars.sort_unstable();
+        vchars.dedup();
+        let chain = |ch: Option<char>, bdn: bool| -> Vec<usize> {
+            self.vct.iter().enumerate().filter(|(i, ct)| {
+                match ct {
+                    CharType::DelimNormal => bdn,
+                    _ => match &vtriggers[*i] {
+                        None => true,
+                        Some(vch) => ch.is_some_and(|ch| vch.contains(&ch)),
+                    },
+                }
+            }).map(|(i, _)| i).collect()
+        };
+        let mut classes = vec![CharClass { vct: chain(None, false), vctdn: chain(None, true), bmarker: false }];
+        let mut ascii = [0; 128];
+        let mut nonascii = HashMap::new();
+        for ch in vchars {
+            let class = CharClass { vct: chain(Some(ch), false), vctdn: chain(Some(ch), true), bmarker: vmchars.contains(&ch) };
+            let id = match classes.iter().position(|c| (c.vct == class.vct) && (c.vctdn == class.vctdn) && (c.bmarker == class.bmarker)) {
+                Some(id) => id,
+                None => {
+                    classes.push(class);
+                    classes.len() - 1
+                }
+            };
+            if ch.is_ascii() {
+                ascii[ch as usize] = id as u16;
+            } else {
+                nonascii.insert(ch, id as u16);
+            }
+        }
+        self.vescape = self.vct.iter().enumerate().filter(|(_, ct)| matches!(ct, CharType::InComment | CharType::EscSeq(_))).map(|(i, _)| i).collect();
+        self.ascii = ascii;
+        self.nonascii = nonascii;
+        self.classes = classes;
+    }
+
+    /// Return the class of the given char
+    pub fn class(&self, ch: char) -> &CharClass {
+        let id = if ch.is_ascii() {
+            self.ascii[ch as usize]
+        } else {
+            self.nonascii.get(&ch).copied().unwrap_or(0)
+        };
+        &self.classes[id as usize]
+    }
+
+    ///
+    /// Add the chartypes wrt the given line and block comment markers, if any.
+    ///
+    /// They are added after the escape sequence chartype, so that a escaped comment
+    /// marker is treated as a normal char, and before all the other chartypes.
+    ///
+    pub fn add_comments(&mut self, comments_line: &[String], comments_block: &[(String, String)]) {
+        if comments_line.is_empty() && comments_block.is_empty() {
+            return;
+        }
+        let mut vct = Vec::new();
+        for comment in comments_line {
+            if comment.is_empty() {
+                continue;
+            }
+            vct.push(CharType::CommentLine(comment.to_string()));
+            self.markers.push(comment.to_string());
+        }
+        for comment in comments_block {
+            if comment.0.is_empty() || comment.1.is_empty() {
+                continue;
+            }
+            vct.push(CharType::CommentBlock(comment.0.to_string(), comment.1.to_string()));
+            self.markers.push(comment.0.to_string());
+            self.markers.push(comment.1.to_string());
+        }
+        self.vct.splice(1..1, vct);
+        self.vct.insert(0, CharType::InComment);
+        self.markers.sort_by_key(|m| std::cmp::Reverse(m.len()));
+    }
+
+    /// Return the length of the longest marker
+    pub fn marker_maxlen(&self) -> usize {
+        self.markers.first().map(|m| m.len()).unwrap_or(0)
+    }
+
+    /// Return the length of the longest marker (including the given normal delimiter, if any),
+    /// which the given string starts with, else 0 if it doesnt start with any of the markers.
+    pub fn marker_len(&self, s: &str, normaldelim: Option<&str>) -> usize {
+        let mut mklen = 0;
+        for m in &self.markers {
+            if s.starts_with(m.as_str()) {
+                mklen = m.len();
+                break;
+            }
+        }
+        if let Some(delim) = normaldelim {
+            if (delim.len() > mklen) && s.starts_with(delim) {
+                mklen = delim.len();
+            }
+        }
+        mklen
     }
 
 }
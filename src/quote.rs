@@ -0,0 +1,165 @@
+//!
+//! Quote - Convert values back into tokens, which tokenise back to the same values
+//! HanishKVC, 2022
+//!
+
+use crate::{TStrX, Error, ErrorKind, DelimDef};
+
+
+/// Serialising related methods, ie the inverse of tokenising
+impl TStrX {
+
+    /// Create a Unrepresentable error wrt the given value, at the given byte offset in it
+    fn error_unrepresentable(value: &str, chpos: usize) -> Error {
+        let ch = value[chpos..].chars().next().unwrap_or(' ');
+        Error::new(ErrorKind::Unrepresentable, ch, value[..chpos].chars().count(), chpos, value.to_string())
+    }
+
+    /// Find the escape sequence char, which maps to the given char. If there are more than
+    /// one such, the char itself is prefered, else the smallest among them is used.
+    fn escseq_for(&self, ch: char) -> Option<char> {
+        if self.escseqs.get(&ch) == Some(&ch) {
+            return Some(ch);
+        }
+        self.escseqs.iter().filter(|(_, v)| **v == ch).map(|(k, _)| *k).min()
+    }
+
+    /// Quote the given value using the specified string quote kind, escaping chars as required.
+    fn quote_with(&self, value: &str, sid: usize) -> Result<String, Error> {
+        let quote = &self.delims.strings[sid];
+        let esc = self.delims.escseq;
+        let mut out = quote.clone();
+        for (chpos, ch) in value.char_indices() {
+            let bspecial = (ch == esc) || value[chpos..].starts_with(quote.as_str());
+            if !self.flags.escapesequences_expand || !(bspecial || ch.is_control()) {
+                if bspecial {
+                    return Err(Self::error_unrepresentable(value, chpos));
+                }
+                out.push(ch);
+                continue;
+            }
+            if let Some(eskey) = self.escseq_for(ch) {
+                out.push(esc);
+                out.push(eskey);
+            } else if self.flags.escapesequences_unicode {
+                out.push_str(&format!("{}u{{{:x}}}", esc, ch as u32));
+            } else if self.flags.escapesequences_hex {
+                out.push_str(&format!("{}x{{{:x}}}", esc, ch as u32));
+            } else if bspecial {
+                return Err(Self::error_unrepresentable(value, chpos));
+            } else {
+                out.push(ch);
+            }
+        }
+        out.push_str(quote);
+        Ok(out)
+    }
+
+    /// Check if tokenising the given line, using the given delimiter, gives the given values.
+    fn roundtrips<T: AsRef<str>>(&self, line: &str, dlimdef: &str, values: &[T]) -> Result<(), usize> {
+        let mut tstr = self.from_str(line, false);
+        let toks = match tstr.tokens_vec(dlimdef, true, false) {
+            Ok(toks) => toks,
+            Err(_) => return Err(0),
+        };
+        for (i, value) in values.iter().enumerate() {
+            if toks.get(i).map(|tok| tok.as_ref()) != Some(value.as_ref()) {
+                return Err(i);
+            }
+        }
+        if toks.len() != values.len() {
+            return Err(values.len().min(toks.len()));
+        }
+        Ok(())
+    }
+
+    ///
+    /// Quote the given value as a string token, so that tokenising it using TStr's
+    /// created from this TStrX, gives back the same value, irrespective of the
+    /// delimiter used. Unlike quote, the value is quoted, even if it doesnt require it.
+    ///
+    fn quote_always(&self, value: &str) -> Result<String, Error> {
+        if self.flags.stringquotes_retain || self.delims.strings.is_empty() {
+            return Err(Self::error_unrepresentable(value, 0));
+        }
+        let mut err = Self::error_unrepresentable(value, 0);
+        for sid in 0..self.delims.strings.len() {
+            match self.quote_with(value, sid) {
+                Ok(quoted) => {
+                    if self.roundtrips(&quoted, " ", &[value]).is_ok() {
+                        return Ok(quoted);
+                    }
+                }
+                Err(qerr) => err = qerr,
+            }
+        }
+        Err(err)
+    }
+
+    ///
+    /// Return the given value as is, if it doesnt require quoting, else quote it as a
+    /// string token, so that tokenising it using TStr's created from this TStrX (with
+    /// the space delimiter) gives back the same value. A quoted value inturn gives
+    /// back the same value, irrespective of the delimiter used.
+    ///
+    /// The value doesnt require quoting, if it is not empty and tokenises back as is.
+    /// To join multiple values using some other delimiter, use join.
+    ///
+    /// The string quote kinds are tried in the order in which they are specified in
+    /// delims.strings, and the 1st one which round trips the value is used. The escape
+    /// char, as well as the string quote (if present in the value), are escaped. So also
+    /// control chars (like newline), for which a escape sequence is available.
+    ///
+    /// A Unrepresentable error is returned, if the value requires quoting, but cant be
+    /// quoted wrt the current characteristics, like if stringquotes_retain is set (as it
+    /// is by default) or escape sequences are not expanded and the value contains the
+    /// string quote.
+    ///
+    pub fn quote(&self, value: &str) -> Result<String, Error> {
+        if !value.is_empty() && self.roundtrips(value, " ", &[value]).is_ok() {
+            return Ok(value.to_string());
+        }
+        self.quote_always(value)
+    }
+
+    ///
+    /// Join the given values into a line, using the given delimiter, so that tokenising
+    /// the line using TStr's created from this TStrX (with the same delimiter and trimming)
+    /// gives back the same values.
+    ///
+    /// The values are left as is, if they are tokenised back as is, else they are quoted.
+    ///
+    /// A Unrepresentable error is returned, if the values cant be joined wrt the current
+    /// characteristics. The error contains the value involved.
+    ///
+    pub fn join<T: AsRef<str>>(&self, values: &[T], dlimdef: impl DelimDef) -> Result<String, Error> {
+        let mut dlimbuf = [0; 4];
+        let dlimdef = dlimdef.dlim_str(&mut dlimbuf);
+        let mut vquoted = Vec::new();
+        for value in values {
+            let value = value.as_ref();
+            if !value.is_empty() && self.roundtrips(value, dlimdef, &[value]).is_ok() {
+                vquoted.push(value.to_string());
+            } else {
+                vquoted.push(self.quote_always(value)?);
+            }
+        }
+        let line = vquoted.join(dlimdef);
+        let i = match self.roundtrips(&line, dlimdef, values) {
+            Ok(()) => return Ok(line),
+            Err(i) => i,
+        };
+        // Retry by quoting all the values, incase a value merged with the delimiter around it
+        let mut vquoted = Vec::new();
+        for value in values {
+            vquoted.push(self.quote_always(value.as_ref())?);
+        }
+        let line = vquoted.join(dlimdef);
+        if self.roundtrips(&line, dlimdef, values).is_ok() {
+            return Ok(line);
+        }
+        let value = values.get(i).map(|value| value.as_ref()).unwrap_or("");
+        Err(Self::error_unrepresentable(value, 0))
+    }
+
+}
@@ -0,0 +1,449 @@
+//!
+//! Reader - Tokenise the contents got from a BufRead, line by line
+//! HanishKVC, 2022
+//!
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::iter::FusedIterator;
+use std::sync::Arc;
+
+use crate::{TStr, TConfig, Token, Error, ErrorKind, DelimDef};
+
+
+/// The default maximum size of the buffer used by TReader
+pub const BUFMAX_DEFAULT: usize = 1 << 20;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// A token extracted by TReader, along with where it starts.
+///
+/// The ranges in the token are byte offsets wrt the stream of lines read,
+/// with their line endings (\n or \r\n) normalised to a single \n.
+///
+pub struct ReaderToken {
+    /// The token
+    pub token: Token<'static>,
+    /// The line (1 based) in which the token starts
+    pub line: usize,
+    /// The char column (1 based) wrt the line, at which the token starts
+    pub col: usize,
+}
+
+
+#[derive(Debug)]
+/// The error returned by TReader
+pub enum ReaderError {
+    /// Reading from the underlying reader failed
+    Io(io::Error),
+    /// Tokenising failed at the given line (1 based) and char column (1 based).
+    /// The positions in the error are wrt the stream of lines read, like in ReaderToken.
+    Tok { err: Error, line: usize, col: usize },
+}
+
+impl fmt::Display for ReaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReaderError::Io(err) => write!(f, "read failed: {}", err),
+            ReaderError::Tok { err, line, col } => write!(f, "{}:{}: {}", line, col, err),
+        }
+    }
+}
+
+impl std::error::Error for ReaderError {}
+
+impl From<io::Error> for ReaderError {
+    fn from(err: io::Error) -> ReaderError {
+        ReaderError::Io(err)
+    }
+}
+
+
+///
+/// Tokenise the contents of a BufRead, one line at a time, with the same
+/// characteristics as TStr. Created using TStrX::reader.
+///
+/// If a string or bracket block (or block comment) is still open at the end
+/// of a line, the following line(s) are read in and joined (using \n) to it,
+/// till the block gets closed, so that such blocks can span multiple lines.
+/// The scan of such a token continues from where it stopped, as each line
+/// is joined, instead of starting over.
+///
+/// Only the text of the token currently being extracted (and what follows it,
+/// on the part of the line already read), is kept in memory. Lines are read in
+/// parts if required, so that long lines with small tokens can be tokenised.
+///
+/// A token, which along with the delimiter ending it, is longer than bufmax
+/// bytes, leads to a TokenTooLong error (at its start). The rest of the token is
+/// then skipped, ie till just after the next delimiter, or till the end of the
+/// line, if it is within a string/bracket block (or block comment), and the
+/// tokenising continues from there.
+///
+pub struct TReader<R: BufRead> {
+    rd: R,
+    /// The tokenisation characteristics (delims, escseqs, flags), shared with others
+    cfg: Arc<TConfig>,
+    /// The maximum size of a token (along with the delimiter ending it), in bytes.
+    /// The buffer can be a few bytes larger, to look ahead of the text being scanned.
+    pub bufmax: usize,
+    /// The text currently buffered, with the lines joined using \n
+    buf: String,
+    /// The byte offset within buf, from where to extract the next token
+    pos: usize,
+    /// The byte offset of the starting of each line in buf. The begining part
+    /// of the 1st line may have been dropped already.
+    linestarts: Vec<usize>,
+    /// The line number (0 based) of the 1st line in buf
+    line0: usize,
+    /// The number of chars of the 1st line in buf, which have been dropped already
+    col0: usize,
+    /// The last (byte offset in buf, line index wrt linestarts, char column) worked out,
+    /// so that the column of the following tokens on the same line can be worked out
+    /// from there.
+    colcache: Cell<Option<(usize, usize, usize)>>,
+    /// The byte offset of buf, wrt the stream of lines read
+    base: usize,
+    /// The char index of buf, wrt the stream of lines read
+    ibase: usize,
+    /// The number of lines read (fully or partly) till now
+    nlines: usize,
+    /// If the last line in buf has been read fully, ie till its line end
+    blinedone: bool,
+    /// The bytes read, which are yet to be added to buf, bcas they dont make up
+    /// a complete utf8 char (or line end) yet
+    pending: Vec<u8>,
+    /// If the reader has reached its end
+    beof: bool,
+    /// If the rest of a too long token is yet to be skipped, along with if it is
+    /// to be skipped till the end of the line
+    skip: Option<bool>,
+    /// If the reader has reached its end, or a read error has stopped the tokenising
+    bdone: bool,
+}
+
+/// The bytes at the end of a partly read line, which are not scanned till more of the
+/// line is read, over and above the longest marker/delimiter, so that the markers and
+/// numeric escape sequences are not split across the parts of the line.
+const SCAN_MARGIN: usize = 16;
+
+impl<R: BufRead> TReader<R> {
+
+    /// Create a TReader, for the given reader, with the given tokenisation characteristics.
+    pub fn new(rd: R, cfg: Arc<TConfig>) -> TReader<R> {
+        TReader {
+            rd,
+            cfg,
+            bufmax: BUFMAX_DEFAULT,
+            buf: String::new(),
+            pos: 0,
+            linestarts: Vec::new(),
+            line0: 0,
+            col0: 0,
+            colcache: Cell::new(None),
+            base: 0,
+            ibase: 0,
+            nlines: 0,
+            blinedone: true,
+            pending: Vec::new(),
+            beof: false,
+            skip: None,
+            bdone: false,
+        }
+    }
+
+    /// The config (tokenisation characteristics) used by this TReader
+    pub fn config(&self) -> &Arc<TConfig> {
+        &self.cfg
+    }
+
+    /// Return the (1 based) line and char column, of the given byte offset in buf
+    fn line_col(&self, pos: usize) -> (usize, usize) {
+        let iline = self.linestarts.partition_point(|&start| start <= pos).saturating_sub(1);
+        let col = match self.colcache.get() {
+            Some((cpos, cline, ccol)) if (cline == iline) && (cpos <= pos) => ccol + self.buf[cpos..pos].chars().count(),
+            _ => {
+                let start = self.linestarts.get(iline).copied().unwrap_or(0).min(pos);
+                let col0 = if iline == 0 { self.col0 } else { 0 };
+                col0 + self.buf[start..pos].chars().count()
+            }
+        };
+        self.colcache.set(Some((pos, iline, col)));
+        (self.line0 + iline + 1, col + 1)
+    }
+
+    /// Drop the text in buf, which has already been consumed, ie that before pos.
+    fn compact(&mut self) {
+        let drop = self.pos;
+        if drop == 0 {
+            return;
+        }
+        let iline = self.linestarts.partition_point(|&start| start <= drop).saturating_sub(1);
+        let lstart = self.linestarts.get(iline).copied().unwrap_or(0).min(drop);
+        let ldropped = self.buf[lstart..drop].chars().count();
+        self.col0 = if iline == 0 { self.col0 + ldropped } else { ldropped };
+        self.ibase += self.buf[..drop].chars().count();
+        self.buf.drain(..drop);
+        self.linestarts.drain(..iline);
+        for start in self.linestarts.iter_mut() {
+            *start = start.saturating_sub(drop);
+        }
+        self.colcache.set(None);
+        self.line0 += iline;
+        self.base += drop;
+        self.pos = 0;
+    }
+
+    ///
+    /// Read more text into buf, ie the rest of the current line, if it hasnt been read
+    /// fully, else the next line. If bjoin, a new line is joined to the token currently
+    /// being extracted, else it is the start of a new token.
+    ///
+    /// The consumed text is dropped first, and inturn the text from pos (ie the token
+    /// being extracted) is limited to bufmax bytes, plus the given margin (to look
+    /// ahead of the text being scanned), by reading the line in parts if required.
+    ///
+    /// Returns false if the reader has no more text.
+    ///
+    fn fill(&mut self, bjoin: bool, margin: usize) -> Result<bool, ReaderError> {
+        self.compact();
+        let bnewline = self.blinedone;
+        let lineend = if bnewline && (self.nlines > 0) { 1 } else { 0 };
+        let avail = (self.bufmax + margin).saturating_sub(self.buf.len() + lineend);
+        if avail == 0 {
+            return Err(self.error(ErrorKind::TokenTooLong, self.pos));
+        }
+        let mut bytes = std::mem::take(&mut self.pending);
+        let n = (&mut self.rd).take(avail as u64).read_until(b'\n', &mut bytes)?;
+        if n == 0 {
+            self.beof = true;
+            self.blinedone = true;
+            if bytes.is_empty() {
+                return Ok(false);
+            }
+        } else {
+            self.blinedone = bytes.ends_with(b"\n");
+        }
+        let mut keep = 0;
+        if self.blinedone {
+            if bytes.ends_with(b"\n") {
+                bytes.pop();
+                if bytes.ends_with(b"\r") {
+                    bytes.pop();
+                }
+            }
+        } else if bytes.ends_with(b"\r") {
+            // Could be the begining of a \r\n line end
+            keep = 1;
+        } else if let Err(err) = std::str::from_utf8(&bytes) {
+            if err.error_len().is_none() {
+                keep = bytes.len() - err.valid_up_to();
+            }
+        }
+        self.pending = bytes.split_off(bytes.len() - keep);
+        let text = String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if bnewline {
+            if self.nlines > 0 {
+                self.buf.push('\n');
+            }
+            if !bjoin {
+                self.pos = self.buf.len();
+            }
+            self.linestarts.push(self.buf.len());
+            self.nlines += 1;
+        }
+        self.buf.push_str(&text);
+        Ok(true)
+    }
+
+    ///
+    /// Skip the rest of the token, which was found to be too long, ie till just after the
+    /// next delimiter, or till the end of the line, if bline is set.
+    ///
+    /// Returns false if the reader has no more text.
+    ///
+    fn skip_rest(&mut self, dlimdef: &str, bline: bool, margin: usize) -> Result<bool, ReaderError> {
+        loop {
+            let rest = &self.buf[self.pos..];
+            let lineend = rest.find('\n');
+            let dlimpos = if bline || dlimdef.is_empty() { None } else { rest.find(dlimdef) };
+            match (dlimpos, lineend) {
+                (Some(i), Some(l)) if l < i => self.pos += l + 1,
+                (Some(i), _) => self.pos += i + dlimdef.len(),
+                (None, Some(l)) => self.pos += l + 1,
+                (None, None) if self.blinedone => self.pos = self.buf.len(),
+                (None, None) => {
+                    // Keep the part at the end, which could be the begining of the delimiter
+                    let mut keep = self.buf.len().saturating_sub(dlimdef.len().saturating_sub(1)).max(self.pos);
+                    while !self.buf.is_char_boundary(keep) {
+                        keep -= 1;
+                    }
+                    self.pos = keep;
+                    if !self.fill(true, margin)? {
+                        return Ok(false);
+                    }
+                    continue;
+                }
+            }
+            return Ok(true);
+        }
+    }
+
+    /// Create a ReaderError, for the given kind of error at the given byte offset in buf
+    fn error(&self, kind: ErrorKind, pos: usize) -> ReaderError {
+        let ch = self.buf[pos..].chars().next().unwrap_or(' ');
+        let err = Error::new(kind, ch, self.ibase + self.buf[..pos].chars().count(), self.base + pos, String::new());
+        let (line, col) = self.line_col(pos);
+        ReaderError::Tok { err, line, col }
+    }
+
+    /// Adjust the positions of the error got from a TStr over buf, to be wrt the stream
+    fn error_adjust(&self, mut err: Error) -> ReaderError {
+        let (line, col) = self.line_col(err.chpos);
+        err.ipos += self.ibase;
+        err.chpos += self.base;
+        err.blockpos = err.blockpos.map(|pos| pos + self.base);
+        ReaderError::Tok { err, line, col }
+    }
+
+    ///
+    /// Extract the next token, reading in more lines as required.
+    /// Returns None, once all the tokens have been extracted.
+    ///
+    /// The empty token, which corresponds to a line (or its end) containing only
+    /// spaces and or comments (being skipped), is not returned.
+    ///
+    /// NOTE: Look at the doc related to TStr::nexttok_ex for more details.
+    ///
+    pub fn nexttok_ex(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Option<Result<ReaderToken, ReaderError>> {
+        let mut dlimbuf = [0; 4];
+        let dlimdef = dlimdef.dlim_str(&mut dlimbuf);
+        let margin = SCAN_MARGIN + dlimdef.len() + self.cfg.vchartypes.marker_maxlen();
+        loop {
+            if self.bdone {
+                return None;
+            }
+            if let Some(bline) = self.skip.take() {
+                match self.skip_rest(dlimdef, bline, margin) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        self.bdone = true;
+                        return None;
+                    }
+                    Err(err) => {
+                        self.bdone = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+            if self.buf[self.pos..].trim().is_empty() {
+                self.pos = self.buf.len();
+                match self.fill(false, margin) {
+                    Ok(true) => continue,
+                    Ok(false) => {
+                        self.bdone = true;
+                        return None;
+                    }
+                    Err(err) => {
+                        self.bdone = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+            // Scan the token, joining more text as required, continuing the scan from where it stopped.
+            // The scan doesnt go beyond bufmax bytes of the token.
+            let mut scan = None;
+            let mut bfinish = false;
+            let (gotr, npos) = loop {
+                let mut tstr = TStr::from_str_cfg(&self.buf, false, self.cfg.clone());
+                tstr.drop_adjust(self.pos);
+                let remaining = tstr.remaining_len();
+                let more = if self.beof || bfinish {
+                    None
+                } else if self.blinedone {
+                    Some(remaining)
+                } else {
+                    Some(remaining.saturating_sub(margin))
+                };
+                let more = match more {
+                    None if bfinish || (remaining <= self.bufmax) => None,
+                    _ => Some(more.unwrap_or(remaining).min(self.bufmax)),
+                };
+                if let Some(gotr) = tstr.nexttok_resume(dlimdef, btrim, &mut scan, more, None, None) {
+                    break (gotr, self.buf.len() - tstr.remaining_len());
+                }
+                if more == Some(self.bufmax) {
+                    if scan.as_ref().is_some_and(|scan| scan.ended()) {
+                        // The token is complete, so finish it along with the spaces following it in buf
+                        bfinish = true;
+                        continue;
+                    }
+                    let err = self.error(ErrorKind::TokenTooLong, self.pos);
+                    let bline = scan.as_ref().is_some_and(|scan| scan.unterminated().is_some());
+                    self.pos += scan.map_or(0, |scan| scan.resumepos.1);
+                    self.skip = Some(bline);
+                    return Some(Err(err));
+                }
+                if let Err(err) = self.fill(true, margin) {
+                    self.bdone = true;
+                    return Some(Err(err));
+                }
+            };
+            match gotr {
+                Err(err) => {
+                    let err = self.error_adjust(err);
+                    self.pos = npos;
+                    return Some(Err(err));
+                }
+                Ok(None) => {
+                    self.pos = npos;
+                    continue;
+                }
+                Ok(Some(token)) => {
+                    self.pos = npos;
+                    let (line, col) = self.line_col(token.span.start);
+                    let mut token = token.into_owned();
+                    token.span = token.span.start+self.base..token.span.end+self.base;
+                    token.lead = token.lead.start+self.base..token.lead.end+self.base;
+                    token.trail = token.trail.start+self.base..token.trail.end+self.base;
+                    if let Some(rawmap) = token.rawmap.as_mut() {
+                        for seg in rawmap.segs.iter_mut() {
+                            seg.raw = seg.raw.start+self.base..seg.raw.end+self.base;
+                        }
+                    }
+                    return Some(Ok(ReaderToken { token, line, col }));
+                }
+            }
+        }
+    }
+
+    /// Return a iterator over the tokens, extracted from the reader.
+    pub fn tokens<D: DelimDef>(&mut self, dlimdef: D, btrim: bool) -> ReaderTokens<'_, R, D> {
+        ReaderTokens {
+            rd: self,
+            dlimdef,
+            btrim,
+        }
+    }
+
+}
+
+
+/// The iterator over the tokens in a TReader, returned by TReader::tokens.
+pub struct ReaderTokens<'t, R: BufRead, D: DelimDef> {
+    rd: &'t mut TReader<R>,
+    dlimdef: D,
+    btrim: bool,
+}
+
+impl<'t, R: BufRead, D: DelimDef> Iterator for ReaderTokens<'t, R, D> {
+    type Item = Result<ReaderToken, ReaderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rd.nexttok_ex(&self.dlimdef, self.btrim)
+    }
+}
+
+/// Once the TReader is fully consumed (or stopped bcas of a read error), it remains so.
+impl<'t, R: BufRead, D: DelimDef> FusedIterator for ReaderTokens<'t, R, D> {}
@@ -0,0 +1,128 @@
+//!
+//! Recover - Control how the tokenising recovers from errors
+//! HanishKVC, 2022
+//!
+
+use std::borrow::Cow;
+
+use crate::{TStr, Token, TokenType, Error, DelimDef, CookedSeg, RawMap};
+use crate::nexttoken;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How tokens_vec_recover should recover from a error wrt a token
+pub enum Recovery {
+    /// Stop at the 1st error
+    Abort,
+    /// Drop till after the next user specified delimiter (which is not escaped),
+    /// following the error. If the error is within a string or bracket block, the
+    /// delimiter is looked for only after the end of the block.
+    SkipToDelim,
+    /// Drop till after the next newline, following the error, ie the rest of the line.
+    SkipToEol,
+    /// Keep the partial token (as a TokenType::Unknown token), and continue from the
+    /// char after the error, like nexttok_ex does.
+    KeepPartial,
+}
+
+
+/// Error recovery related methods
+impl<'a> TStr<'a> {
+
+    /// Drop till after the next occurance of the given marker, which is not escaped,
+    /// or till the end, if there is no such occurance.
+    fn skip_past(&mut self, marker: &str) {
+        let mut bescape = false;
+        for (pos, ch) in self.theStr.char_indices() {
+            if bescape {
+                bescape = false;
+                continue;
+            }
+            if ch == self.cfg.delims.escseq {
+                bescape = true;
+                continue;
+            }
+            if self.theStr[pos..].starts_with(marker) {
+                self.drop_adjust(pos + marker.len());
+                return;
+            }
+        }
+        self.drop_adjust(self.theStr.len());
+    }
+
+    ///
+    /// Drop the rest of the token, in which a error was found, by continuing its scan from
+    /// after the error, ignoring any further errors. So any string or bracket block, which
+    /// was open at the error, is skipped past its closer, before looking for the delimiter.
+    ///
+    /// thestr is the current string as it was, when the token was being extracted.
+    ///
+    pub(crate) fn skip_token(&mut self, dlimdef: &str, btrim: bool, thestr: &'a str, mut scan: Option<nexttoken::Scan>) {
+        while scan.is_some() {
+            self.theStr = thestr;
+            match self.nexttok_resume(dlimdef, btrim, &mut scan, None, None, None) {
+                Some(Err(_)) => continue,
+                _ => break,
+            }
+        }
+    }
+
+    ///
+    /// Get a vector of all the tokens in the current string/line, along with
+    /// the list of errors found, if any.
+    ///
+    /// The recovery policy specifies how to continue after a error. The errors
+    /// contain their positions wrt the original string, as returned by nexttok_ex.
+    ///
+    /// NOTE: Look at the doc related to nexttok_ex for more details.
+    ///
+    pub fn tokens_vec_recover(&mut self, dlimdef: impl DelimDef, btrim: bool, recovery: Recovery) -> (Vec<Token<'a>>, Vec<Error>) {
+        let mut dlimbuf = [0; 4];
+        let dlimdef = dlimdef.dlim_str(&mut dlimbuf);
+        let mut vtoks = Vec::new();
+        let mut verrs = Vec::new();
+        while self.remaining_len() > 0 {
+            let lstart = self.orig_offset();
+            let tstart = lstart + (self.theStr.len() - self.theStr.trim_start().len());
+            let thestr = self.theStr;
+            let mut scan = None;
+            let err = match self.nexttok_resume(dlimdef, btrim, &mut scan, None, None, None).unwrap_or(Ok(None)) {
+                Ok(tok) => {
+                    vtoks.extend(tok);
+                    continue;
+                }
+                Err(err) => err,
+            };
+            match recovery {
+                Recovery::Abort => {
+                    verrs.push(err);
+                    break;
+                }
+                Recovery::SkipToDelim => self.skip_token(dlimdef, btrim, thestr, scan),
+                Recovery::SkipToEol => self.skip_past("\n"),
+                Recovery::KeepPartial => {
+                    let tend = self.orig_offset().max(tstart);
+                    // Map the partial token text to the original string, like for any other token
+                    let rawmap = match &scan {
+                        Some(scan) if self.cfg.flags.cooked_map => Some(RawMap {
+                            raw: Cow::Borrowed(&self.theOrig[tstart..tend]),
+                            segs: CookedSeg::clip(&scan.segs, 0..err.tok.len(), lstart),
+                        }),
+                        _ => None,
+                    };
+                    vtoks.push(Token {
+                        text: Cow::Owned(err.tok.clone()),
+                        toktype: TokenType::Unknown,
+                        span: tstart..tend,
+                        lead: lstart..tstart,
+                        trail: tend..tend,
+                        rawmap,
+                    });
+                }
+            }
+            verrs.push(err);
+        }
+        (vtoks, verrs)
+    }
+
+}
@@ -10,21 +10,23 @@
 //!   about unused / dead code wrt the library's methods etal
 //!
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::{TStr, Flags, TStrX, Delimiters};
+use crate::diag::{self, Style};
+use crate::{TStr, TConfig, Flags, TStrX, Delimiters, ErrorKind, TokenType, TokenTree, Recovery, Location, TString};
 
 const MTAG: &str = "TEST:TestLib";
 
 pub fn test_create() {
     let mtag = format!("{}:TestCreate", MTAG);
     let mut str1 = TStr {
+        theOrig: "A direct string string",
         theStr: "A direct string string",
         trimmedPrefixCnt: 0,
         trimmedSuffixCnt: 0,
-        escSeqMap: HashMap::new(),
-        flags: Flags::default(),
-        delims: Delimiters::default(),
+        cfg: Arc::new(TConfig::new(Delimiters::default(), HashMap::new(), Flags::default())),
     };
     let thestring = "  A string 21string ".to_string();
     let mut str2 = TStr::from_str(&thestring, false);
@@ -100,16 +102,43 @@ pub fn test_nexttoken() {
 
 pub fn test_peel_bracket() {
     let mut tstr = TStr::from_str("testbracket( 123, abc, \" msg inside bracket\")", false);
-    let prefix = tstr.peel_bracket('(').unwrap();
-    print!("TEST:PeelBracket:prefix[{}], contents[{}]\n", prefix, tstr.the_str());
+    let block = tstr.peel_bracket(Some("(")).unwrap();
+    print!("TEST:PeelBracket:prefix[{}], contents[{}]\n", block.prefix, tstr.the_str());
+    assert_eq!(block.prefix, "testbracket");
+    assert_eq!(block.contents, tstr.the_str());
+
+    // The block is validated, and errors are returned instead of panicing
+    let checks = [
+        ("", ErrorKind::BracketNotFound, 0),
+        ("   ", ErrorKind::BracketNotFound, 3),
+        ("test 123", ErrorKind::BracketNotFound, 0),
+        ("test(123", ErrorKind::UnterminatedBracket, 4),
+        ("test(123) 456", ErrorKind::JunkAfterBlockToken, 10),
+        ("test(1)(2)", ErrorKind::JunkAfterBlockToken, 7),
+        ("test(a))", ErrorKind::JunkAfterBlockToken, 7),
+        ("foo bar(x)", ErrorKind::BracketNotFound, 0),
+        (r#"a")"(x)"#, ErrorKind::UnbalancedClosingBracket, 2),
+        (r"test\( (a)", ErrorKind::BracketNotFound, 0),
+    ];
+    for (sstr, kind, chpos) in checks {
+        let mut tstr = TStr::from_str(sstr, false);
+        let err = tstr.peel_bracket(None).unwrap_err();
+        println!("TEST:PeelBracket:[{}]:Expected Failure:{}", sstr, err);
+        assert_eq!((err.kind, err.chpos), (kind, chpos));
+    }
+    let mut tstr = TStr::from_str(r#" test\((a, ")", (b)) "#, false);
+    let block = tstr.peel_bracket(None).unwrap();
+    assert_eq!((block.prefix.as_ref(), block.kind, block.contents.as_ref()), (r"test\(", 0, r#"a, ")", (b)"#));
+    let mut tstr = TStr::from_str("test(a)", false);
+    assert_eq!(tstr.peel_bracket(Some("[")).unwrap_err().kind, ErrorKind::BracketNotFound);
 }
 
 pub fn test_peel_string() {
     let tstrx = TStrX::new();
-    let delim = '"';
+    let delim = "\"";
     let tstr = tstrx.from_str("   \"A string with double quote at one end  ", false);
     let mut rstr = tstr.clone();
-    let gotr = rstr.peel_string(delim);
+    let gotr = rstr.peel_string(Some(delim));
     if gotr.is_err() {
         print!("TEST:PeelString:[{}]:StringDelim:{}:Expected Failure:{}\n", tstr, delim, gotr.unwrap_err());
     } else {
@@ -117,12 +146,34 @@ pub fn test_peel_string() {
     }
     let tstr = tstrx.from_str("              \"A string with double quote at both end\"  ", false);
     let mut rstr = tstr.clone();
-    let gotr = rstr.peel_string(delim);
+    let gotr = rstr.peel_string(Some(delim));
     if gotr.is_err() {
         print!("TEST:PeelString:[{}]:StringDelim:{}:Unexpected Failure:{}\n", tstr, delim, gotr.unwrap_err());
     } else {
         print!("DBUG:PeelString:[{}]:StringDelim:{}:Expected Success:{}\n", tstr, delim, rstr);
     }
+
+    // Escape sequences are expanded, while a escaped quote doesnt end the string
+    let mut tstr = tstrx.from_str(r#" "say \"hi\"\tthere " "#, false);
+    assert_eq!(tstr.peel_string(None).unwrap(), "say \"hi\"\tthere ");
+    assert_eq!(tstr.the_str(), r#"say \"hi\"\tthere "#);
+    let mut tstr = tstrx.from_str(r#""plain""#, false);
+    assert!(matches!(tstr.peel_string(None).unwrap(), Cow::Borrowed("plain")));
+    let checks = [
+        ("", ErrorKind::StringNotFound, 0),
+        ("abc", ErrorKind::StringNotFound, 0),
+        (r#""abc\""#, ErrorKind::UnterminatedString, 0),
+        (r#""abc" def"#, ErrorKind::JunkAfterBlockToken, 6),
+        (r#""abc\q""#, ErrorKind::UnknownEscSeq, 5),
+    ];
+    for (sstr, kind, chpos) in checks {
+        let mut tstr = tstrx.from_str(sstr, false);
+        let err = tstr.peel_string(None).unwrap_err();
+        println!("TEST:PeelString:[{}]:Expected Failure:{}", sstr, err);
+        assert_eq!((err.kind, err.chpos), (kind, chpos));
+    }
+    let mut tstr = tstrx.from_str(r#""abc""#, false);
+    assert_eq!(tstr.peel_string(Some("'")).unwrap_err().kind, ErrorKind::StringNotFound);
 }
 
 pub fn test_first_nth_last() {
@@ -171,10 +222,10 @@ pub fn test_tstrx() {
     let sstr1 = "    test,   me  ";
     let sstr2 = "    test,   [me, hello world], [[save nature], [save earth]]  ";
     let mut tstrx = TStrX::new();
-    tstrx.flags.trim = false;
-    tstrx.flags.mainbracket_beginprefixed = false;
+    tstrx.flags_mut().trim = false;
+    tstrx.flags_mut().mainbracket_beginprefixed = false;
     tstrx.escseqs_set('v', 'W');
-    tstrx.delims.bracket = ('[',']');
+    tstrx.delims_mut().brackets = vec![("[".to_string(), "]".to_string())];
 
     let mut tstr = tstrx.from_str(sstr1, true);
     print!("TEST:TStrX:Trimmed:[{:?}]\n", tstr.tokens_vec(',', true, false).unwrap());
@@ -187,8 +238,8 @@ pub fn test_tstrx() {
 pub fn test_string_subparts() {
     let sstr1 = r#""skey1":"svalue2","skey2":"svalue2", what else, "nothing new" another, "again again", the end"#;
     let mut tstrx = TStrX::new();
-    tstrx.flags.string_canbe_asubpart = true;
-    tstrx.flags.blocktok_dlimuser_endreqd = false;
+    tstrx.flags_mut().string_canbe_asubpart = true;
+    tstrx.flags_mut().blocktok_dlimuser_endreqd = false;
     let mut tstr = tstrx.from_str(sstr1, true);
     let toks = tstr.tokens_vec(',', true, false).unwrap();
     println!("TEST:StringSubParts:>>{}<<:>>{:?}<<", sstr1, toks);
@@ -197,10 +248,1002 @@ pub fn test_string_subparts() {
 pub fn test_multibrackets() {
     let sstr1 = r#""skey1":"svalue2","skey2":"svalue2", what else, ["nothing new" another], {"again again", the end}, "skey3":[{what now}, {again}]  , no more"#;
     let mut tstr = TStr::from_str(sstr1, true);
-    tstr.delims.bracket = ('{','}');
-    tstr.delims.obracket = Some(('[',']'));
-    tstr.flags.string_canbe_asubpart = true;
-    tstr.flags.blocktok_dlimuser_endreqd = false;
+    tstr.config_update(|x| {
+        x.delims_mut().brackets = vec![("{".to_string(), "}".to_string()), ("[".to_string(), "]".to_string())];
+        x.flags_mut().string_canbe_asubpart = true;
+        x.flags_mut().blocktok_dlimuser_endreqd = false;
+    });
     let toks = tstr.tokens_vec(',', true, false).unwrap();
     println!("TEST:MultiBrackets:>>{}<<:>>{:#?}<<", sstr1, toks);
 }
+
+pub fn test_errors_ex(tstrx: &TStrX, sstr: &str, kind: ErrorKind, ipos: usize) {
+    let mut tstr = tstrx.from_str(sstr, true);
+    let gotr = tstr.nexttok(' ', true);
+    println!("TEST:Errors:>>{}<<:>>{:?}<<", sstr, gotr);
+    let err = gotr.unwrap_err();
+    assert_eq!(err.kind, kind);
+    assert_eq!(err.ipos, ipos);
+}
+
+pub fn test_errors() {
+    let mut tstrx = TStrX::new();
+    test_errors_ex(&tstrx, r"abc\q def", ErrorKind::UnknownEscSeq, 4);
+    test_errors_ex(&tstrx, "abc) def", ErrorKind::UnbalancedClosingBracket, 3);
+    test_errors_ex(&tstrx, ") def", ErrorKind::UnbalancedClosingBracket, 0);
+    test_errors_ex(&tstrx, "  \"abc def", ErrorKind::UnterminatedString, 2);
+    test_errors_ex(&tstrx, "abc(def (1,2) ", ErrorKind::UnterminatedBracket, 3);
+    test_errors_ex(&tstrx, "\"abc\"def ", ErrorKind::JunkAfterBlockToken, 5);
+    tstrx.flags_mut().mainbracket_beginstandalone = false;
+    tstrx.flags_mut().mainbracket_beginprefixed = false;
+    test_errors_ex(&tstrx, "(abc) def", ErrorKind::BracketNotAllowedAtStart, 0);
+    test_errors_ex(&tstrx, "abc(def) ", ErrorKind::BracketNotAllowedInMiddle, 3);
+}
+
+pub fn test_token_spans() {
+    let sstr = r#"  abc  , "a str" ,  f(1, 2)  , x\ty "#;
+    let mut tstr = TStr::from_str(sstr, false);
+    let toks = tstr.tokens_vec_ex(',', true, false).unwrap();
+    println!("TEST:TokenSpans:>>{}<<:>>{:#?}<<", sstr, toks);
+    assert_eq!(toks.len(), 4);
+    let types = [TokenType::Normal, TokenType::String(0), TokenType::BracketPrefixed, TokenType::Normal];
+    for i in 0..toks.len() {
+        assert_eq!(toks[i].toktype, types[i]);
+        assert!(sstr[toks[i].lead.clone()].trim().is_empty());
+        assert!(sstr[toks[i].trail.clone()].trim().is_empty());
+        assert_eq!(toks[i].lead.end, toks[i].span.start);
+        assert_eq!(toks[i].trail.start, toks[i].span.end);
+    }
+    assert_eq!(&sstr[toks[0].span.clone()], "abc");
+    assert_eq!(toks[0].lead, 0..2);
+    assert_eq!(toks[0].trail, 5..7);
+    // The lead and trail of adjacent tokens meet at the delimiter, so the spans cover the input
+    for i in 1..toks.len() {
+        assert_eq!(&sstr[toks[i-1].trail.end..toks[i].lead.start], ",");
+    }
+    assert_eq!(&sstr[toks[1].span.clone()], "\"a str\"");
+    assert_eq!(toks[1].lead, 8..9);
+    assert_eq!(&sstr[toks[2].span.clone()], "f(1, 2)");
+    assert_eq!(toks[2].lead, 18..20);
+    assert_eq!(&sstr[toks[3].span.clone()], r"x\ty");
+    assert_eq!(toks[3].text, "x\ty");
+
+    let mut tstr = TStr::from_str(sstr, true);
+    let toks = tstr.splitn_ex(2, ',').unwrap();
+    assert_eq!(&sstr[toks[0].span.clone()], "abc");
+    assert_eq!(&sstr[toks[1].span.clone()], toks[1].text);
+    assert_eq!(toks[1].span.end, sstr.trim_end().len());
+
+    // Error positions are wrt the original string
+    let sstr = "abc, def, g)h";
+    let mut tstr = TStr::from_str(sstr, false);
+    let err = tstr.tokens_vec_ex(',', true, false).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnbalancedClosingBracket);
+    assert_eq!(err.chpos, 11);
+    assert_eq!(err.ipos, 11);
+}
+
+pub fn test_tokens_iter() {
+    let sstr = "  one, two(2, 3) , \"three\", four) , five ";
+    let mut tstr = TStr::from_str(sstr, false);
+    let toks: Vec<Result<String, ErrorKind>> = tstr.tokens(',', true).map(|tok| tok.map(|tok| tok.text.into_owned()).map_err(|err| err.kind)).collect();
+    println!("TEST:TokensIter:>>{}<<:>>{:?}<<", sstr, toks);
+    // the rest of four, after the error, is skipped
+    let expected = vec![Ok("one"), Ok("two(2, 3)"), Ok("\"three\""), Err(ErrorKind::UnbalancedClosingBracket), Ok("five")];
+    assert_eq!(toks, expected.into_iter().map(|tok| tok.map(String::from)).collect::<Vec<_>>());
+
+    let mut tstr = TStr::from_str(sstr, true);
+    let mut iter = tstr.tokens(',', true);
+    for _ in 0..3 {
+        let tok = iter.next().unwrap().unwrap();
+        assert_eq!(&sstr[tok.span.clone()], tok.text);
+    }
+    // the error is wrt the original string, and the iterator continues from the next token
+    let err = iter.next().unwrap().unwrap_err();
+    assert_eq!((err.kind, err.chpos), (ErrorKind::UnbalancedClosingBracket, sstr.find("four)").unwrap() + 4));
+    let tok = iter.next().unwrap().unwrap();
+    assert_eq!(tok.text, "five");
+    assert_eq!(&sstr[tok.span.clone()], "five");
+    assert!(iter.next().is_none());
+    assert!(iter.next().is_none());
+}
+
+pub fn test_tokens_borrow() {
+    let sstr = r#"plain, "quoted str", esc\tseq, f(a, "b"),  spaced  "#;
+    let mut tstrx = TStrX::new();
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec_ex(',', true, false).unwrap();
+    println!("TEST:TokensBorrow:>>{}<<:>>{:?}<<", sstr, toks);
+    let bborrowed = [true, true, false, true, true];
+    for i in 0..toks.len() {
+        assert_eq!(matches!(toks[i].text, Cow::Borrowed(_)), bborrowed[i]);
+    }
+    assert_eq!(toks[2].text, "esc\tseq");
+    assert_eq!(toks[4].text, "spaced");
+
+    tstrx.flags_mut().stringquotes_retain = false;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec(',', true, false).unwrap();
+    assert_eq!(toks[1], "quoted str");
+    assert!(matches!(toks[1], Cow::Borrowed(_)));
+
+    tstrx.flags_mut().stringquotes_retain = true;
+    tstrx.flags_mut().string_canbe_asubpart = true;
+    let mut tstr = tstrx.from_str(r#"key:"a value""#, true);
+    let tok = tstr.nexttok(',', true).unwrap();
+    assert_eq!(tok, r#"key:"a value""#);
+    assert!(matches!(tok, Cow::Borrowed(_)));
+}
+
+pub fn test_multichar_markers() {
+    let mut tstr = TStr::from_str(" a :: b c::d :: f(x::y) ", true);
+    let toks = tstr.tokens_vec("::", true, false).unwrap();
+    println!("TEST:MultiCharMarkers:Delim:>>{:?}<<", toks);
+    assert_eq!(toks, vec!["a", "b c", "d", "f(x::y)"]);
+
+    let mut tstr = TStr::from_str("x => y, z => w", true);
+    let (tok, rem) = tstr.split_once("=>").unwrap();
+    assert_eq!(tok, "x");
+    assert_eq!(rem, "y, z => w");
+
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().strings = vec!["\"\"\"".to_string()];
+    tstrx.delims_mut().brackets = vec![("<".to_string(), ">".to_string()), ("<%".to_string(), "%>".to_string())];
+    tstrx.escseqs_update();
+    let sstr = r#""""triple "quoted" str""" tag<%a <b> %> <% c, "d" %> <e<%f%>>"#;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    println!("TEST:MultiCharMarkers:Blocks:>>{}<<:>>{:#?}<<", sstr, toks);
+    let texts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    assert_eq!(texts, vec![r#""""triple "quoted" str""""#, "tag<%a <b> %>", r#"<% c, "d" %>"#, "<e<%f%>>"]);
+    let types = [TokenType::String(0), TokenType::BracketPrefixed, TokenType::BracketStandalone, TokenType::BracketStandalone];
+    for i in 0..toks.len() {
+        assert_eq!(toks[i].toktype, types[i]);
+        assert_eq!(&sstr[toks[i].span.clone()], toks[i].text);
+    }
+
+    let mut tstr = tstrx.from_str("tag<%a <b> %>", true);
+    let block = tstr.peel_bracket(None).unwrap();
+    assert_eq!(block.prefix, "tag");
+    assert_eq!(tstr.the_str(), "a <b> ");
+    let mut tstr = tstrx.from_str(r#" """a "b" c""" "#, true);
+    tstr.peel_string(Some("\"\"\"")).unwrap();
+    assert_eq!(tstr.the_str(), r#"a "b" c"#);
+}
+
+pub fn test_multistrings() {
+    let sstr = r#"  "double 'q' str" 'single "q" str' `back tick` mixed"a'b"  'it\'s' "#;
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().strings = vec!["\"".to_string(), "'".to_string(), "`".to_string()];
+    tstrx.flags_mut().string_canbe_asubpart = true;
+    tstrx.escseqs_update();
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    println!("TEST:MultiStrings:>>{}<<:>>{:#?}<<", sstr, toks);
+    let texts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    assert_eq!(texts, vec![r#""double 'q' str""#, r#"'single "q" str'"#, "`back tick`", r#"mixed"a'b""#, "'it's'"]);
+    let types = [TokenType::String(0), TokenType::String(1), TokenType::String(2), TokenType::String(0), TokenType::String(1)];
+    for i in 0..toks.len() {
+        assert_eq!(toks[i].toktype, types[i]);
+    }
+
+    let mut tstr = tstrx.from_str(r#"'not closed" "#, true);
+    let err = tstr.nexttok(' ', true).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnterminatedString);
+    assert_eq!(err.ch, '\'');
+}
+
+pub fn test_bracket_kinds() {
+    let sstr = r#"a(1, [2]) b[x, (y)] {k: <v>} <t, {u}> c{"s"}"#;
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().brackets = vec![
+        ("(".to_string(), ")".to_string()),
+        ("[".to_string(), "]".to_string()),
+        ("{".to_string(), "}".to_string()),
+        ("<".to_string(), ">".to_string()),
+    ];
+    tstrx.escseqs_update();
+    // all the bracket chars should be escapable
+    for b in ['[', ']', '{', '}', '<', '>'] {
+        let sesc = format!("a\\{}b", b);
+        let mut tstr = tstrx.from_str(&sesc, true);
+        assert_eq!(tstr.nexttok(' ', true).unwrap(), format!("a{}b", b));
+    }
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    println!("TEST:BracketKinds:>>{}<<:>>{:#?}<<", sstr, toks);
+    let texts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    assert_eq!(texts, vec!["a(1, [2])", "b[x, (y)]", "{k: <v>}", "<t, {u}>", r#"c{"s"}"#]);
+
+    let bracketed = [("a(1, [2])", "a", "1, [2]"), ("b[x, (y)]", "b", "x, (y)"), ("{k: <v>}", "", "k: <v>"), ("<t, {u}>", "", "t, {u}")];
+    for (bstr, bprefix, bcontents) in bracketed {
+        let mut tstr = tstrx.from_str(bstr, true);
+        let block = tstr.peel_bracket(None).unwrap();
+        assert_eq!(block.prefix, bprefix);
+        assert_eq!(tstr.the_str(), bcontents);
+    }
+}
+
+pub fn test_bracket_strict() {
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().brackets = vec![
+        ("(".to_string(), ")".to_string()),
+        ("[".to_string(), "]".to_string()),
+        ("{".to_string(), "}".to_string()),
+    ];
+    tstrx.escseqs_update();
+    let sstr = "a{ [ } ] b( [ ) c";
+    // By default only the outermost bracket type is counted
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec(' ', true, true).unwrap();
+    assert_eq!(toks, vec!["a{ [ }", "b( [ )", "c"]);
+    tstrx.flags_mut().bracket_strict = true;
+    let mut tstr = tstrx.from_str("f(a, [b, {c}], (d)) g", true);
+    let toks = tstr.tokens_vec(' ', true, false).unwrap();
+    assert_eq!(toks, vec!["f(a, [b, {c}], (d))", "g"]);
+    let mut tstr = tstrx.from_str(sstr, true);
+    let err = tstr.nexttok(' ', true).unwrap_err();
+    println!("TEST:BracketStrict:>>{}<<:>>{}<<", sstr, err);
+    assert_eq!(err.kind, ErrorKind::MismatchedBracket);
+    assert_eq!(err.ch, '}');
+    assert_eq!(err.chpos, 5);
+    assert_eq!(err.blockpos, Some(3));
+    let mut tstr = tstrx.from_str("x b( [ ) c", true);
+    tstr.nexttok(' ', true).unwrap();
+    let err = tstr.nexttok(' ', true).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::MismatchedBracket);
+    assert_eq!((err.chpos, err.blockpos), (7, Some(5)));
+    let mut tstr = tstrx.from_str("x b( [ ] ", true);
+    tstr.nexttok(' ', true).unwrap();
+    let err = tstr.nexttok(' ', true).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnterminatedBracket);
+    assert_eq!((err.chpos, err.blockpos), (3, Some(3)));
+}
+
+pub fn test_bracket_strings() {
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().strings = vec!["\"".to_string(), "'".to_string()];
+    tstrx.escseqs_update();
+    let sstr = r#"f("(", x) g(')', "'(", "\"(") h(a, b)"#;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    println!("TEST:BracketStrings:>>{}<<:>>{:#?}<<", sstr, toks);
+    let texts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    assert_eq!(texts, vec![r#"f("(", x)"#, r#"g(')', "'(", ""(")"#, "h(a, b)"]);
+    assert_eq!(&sstr[toks[1].span.clone()], r#"g(')', "'(", "\"(")"#);
+
+    tstrx.flags_mut().bracket_strict = true;
+    tstrx.delims_mut().brackets.push(("[".to_string(), "]".to_string()));
+    tstrx.escseqs_update();
+    let mut tstr = tstrx.from_str(r#"f("[", ']', [")"]) x"#, true);
+    let toks = tstr.tokens_vec(' ', true, false).unwrap();
+    assert_eq!(toks, vec![r#"f("[", ']', [")"])"#, "x"]);
+
+    let mut tstr = tstrx.from_str(r#"f("(, x) g"#, true);
+    let err = tstr.nexttok(' ', true).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnterminatedString);
+    assert_eq!(err.chpos, 2);
+}
+
+pub fn test_comments() {
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().strings = vec!["\"".to_string(), "'".to_string()];
+    tstrx.delims_mut().comments_line = vec!["#".to_string(), "//".to_string()];
+    tstrx.delims_mut().comments_block = vec![("/*".to_string(), "*/".to_string())];
+    tstrx.escseqs_update();
+
+    // Comments are skipped, but not within strings
+    let sstr = r#"a "b # c" /* d */ e/* f */g f(1, /* ) */ 2) h # i j"#;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    println!("TEST:Comments:>>{}<<:>>{:#?}<<", sstr, toks);
+    let texts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    assert_eq!(texts, vec!["a", "\"b # c\"", "eg", "f(1, /* ) */ 2)", "h"]);
+    assert_eq!(&sstr[toks[2].span.clone()], "e/* f */g");
+
+    let mut tstr = tstrx.from_str("a, b // c, d\ne, f", true);
+    let toks = tstr.tokens_vec(',', true, false).unwrap();
+    assert_eq!(toks, vec!["a", "b", "e", "f"]);
+
+    let mut tstr = tstrx.from_str(r"a x\#y b", true);
+    let toks = tstr.tokens_vec(' ', true, false).unwrap();
+    assert_eq!(toks, vec!["a", "x#y", "b"]);
+
+    // A skipped comment on its own doesnt give a token
+    for sstr in ["# only", "  /* x */ ", "/* x */ # y"] {
+        let mut tstr = tstrx.from_str(sstr, false);
+        assert!(tstr.tokens_vec(',', true, false).unwrap().is_empty());
+        let mut tstr = tstrx.from_str(sstr, false);
+        assert_eq!(tstr.tokens(' ', true).count(), 0);
+        assert_eq!(tstr.remaining_len(), 0);
+    }
+
+    // Return comments as tokens
+    tstrx.flags_mut().comments_retain = true;
+    let mut tstr = tstrx.from_str("a/* b */c # d e", true);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    let texts: Vec<(&str, TokenType)> = toks.iter().map(|tok| (tok.text.as_ref(), tok.toktype.clone())).collect();
+    assert_eq!(texts, vec![("a", TokenType::Normal), ("/* b */", TokenType::Comment), ("c", TokenType::Normal), ("# d e", TokenType::Comment)]);
+
+    // Nestable block comments
+    tstrx.flags_mut().comments_retain = false;
+    let mut tstr = tstrx.from_str("a /* b /* c */ d */ e", true);
+    let toks = tstr.tokens_vec(' ', true, false).unwrap();
+    assert_eq!(toks, vec!["a", "d", "*/", "e"]);
+    tstrx.flags_mut().comment_block_nestable = true;
+    let mut tstr = tstrx.from_str("a /* b /* c */ d */ e", true);
+    let toks = tstr.tokens_vec(' ', true, false).unwrap();
+    assert_eq!(toks, vec!["a", "e"]);
+
+    let mut tstr = tstrx.from_str("a /* b /* c */ d", true);
+    assert_eq!(tstr.nexttok(' ', true).unwrap(), "a");
+    let err = tstr.nexttok(' ', true).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnterminatedComment);
+    assert_eq!(err.chpos, 2);
+}
+
+pub fn test_reader() {
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().comments_line = vec!["#".to_string()];
+    tstrx.escseqs_update();
+    let sdata = "a \"b\r\nc\" # d\r\n\n   \n# e\nf(1,\n 2, \"(\"\n) g\nh \"i";
+    let mut rd = tstrx.reader(sdata.as_bytes());
+    let toks: Vec<_> = rd.tokens(' ', true).collect();
+    println!("TEST:Reader:>>{:?}<<:>>{:#?}<<", sdata, toks);
+    let mut got = Vec::new();
+    for tok in &toks[..toks.len()-1] {
+        let tok = tok.as_ref().unwrap();
+        got.push((tok.token.text.to_string(), tok.line, tok.col));
+    }
+    assert_eq!(got, vec![
+        ("a".to_string(), 1, 1), ("\"b\nc\"".to_string(), 1, 3),
+        ("f(1,\n 2, \"(\"\n)".to_string(), 6, 1), ("g".to_string(), 8, 3),
+        ("h".to_string(), 9, 1),
+    ]);
+    // The spans are wrt the lines read, with line endings normalised to \n
+    let snorm = sdata.replace("\r\n", "\n");
+    let tok = toks[2].as_ref().unwrap();
+    assert_eq!(&snorm[tok.token.span.clone()], "f(1,\n 2, \"(\"\n)");
+    match toks.last().unwrap() {
+        Err(crate::ReaderError::Tok { err, line, col }) => {
+            assert_eq!(err.kind, ErrorKind::UnterminatedString);
+            assert_eq!((*line, *col), (9, 3));
+            assert_eq!(err.chpos, snorm.rfind('"').unwrap());
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // Tokens needing more than the allowed buffer
+    let sdata = "a (b\nc\nd\ne) f";
+    let mut rd = tstrx.reader(sdata.as_bytes());
+    rd.bufmax = 8;
+    assert_eq!(rd.nexttok_ex(' ', true).unwrap().unwrap().token.text, "a");
+    match rd.nexttok_ex(' ', true).unwrap() {
+        Err(crate::ReaderError::Tok { err, .. }) => assert_eq!(err.kind, ErrorKind::TokenTooLong),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(rd.nexttok_ex(' ', true).is_none());
+
+    // A too long token is reported, and skipped past, while a token which fits in bufmax
+    // is not affected by the bytes kept to look ahead of the scan.
+    let sdata = format!("aaaaaaaaaa {} c {} e\n\"f{}\" g\nh", "b".repeat(37), "d".repeat(19), "x ".repeat(20));
+    let mut rd = tstrx.reader(sdata.as_bytes());
+    rd.bufmax = 20;
+    let toks: Vec<_> = rd.tokens(' ', true).collect();
+    println!("TEST:Reader:TooLong:>>{}<<:>>{:?}<<", sdata, toks);
+    let mut texts = Vec::new();
+    for tok in &toks {
+        match tok {
+            Ok(tok) => texts.push(tok.token.text.to_string()),
+            Err(crate::ReaderError::Tok { err, line, col }) => {
+                assert_eq!(err.kind, ErrorKind::TokenTooLong);
+                texts.push(format!("{}:{}", line, col));
+            }
+            Err(err) => panic!("unexpected {:?}", err),
+        }
+    }
+    assert_eq!(texts, vec!["aaaaaaaaaa".to_string(), "1:12".to_string(), "c".to_string(), "d".repeat(19), "e".to_string(), "2:1".to_string(), "h".to_string()]);
+
+    // A long line of small tokens, is read in parts, wrt bufmax
+    let words: Vec<String> = (0..200).map(|i| format!("é{}\\\"", i)).collect();
+    let sdata = format!("{}\n(x\ny) \"{}\" z", words.join(" "), "ab ".repeat(40));
+    let mut rd = tstrx.reader(sdata.as_bytes());
+    rd.bufmax = 256;
+    let toks: Vec<_> = rd.tokens(' ', true).map(|tok| tok.unwrap()).collect();
+    assert_eq!(toks.len(), 203);
+    let mut col = 1;
+    for (tok, word) in toks.iter().zip(&words) {
+        assert_eq!(tok.token.text, word.replace("\\\"", "\""));
+        assert_eq!((tok.line, tok.col), (1, col));
+        col += word.chars().count() + 1;
+    }
+    assert_eq!((toks[200].token.text.as_ref(), toks[200].line, toks[200].col), ("(x\ny)", 2, 1));
+    assert_eq!((toks[201].line, toks[201].col), (3, 4));
+    assert_eq!((toks[202].token.text.as_ref(), toks[202].line, toks[202].col), ("z", 3, 127));
+    assert_eq!(&sdata[toks[202].token.span.clone()], "z");
+
+    // A block spanning many lines
+    let sdata = format!("a ({}) b", "x,\n".repeat(20000));
+    let mut rd = tstrx.reader(sdata.as_bytes());
+    let toks: Vec<_> = rd.tokens(' ', true).map(|tok| tok.unwrap()).collect();
+    assert_eq!(toks.len(), 3);
+    assert_eq!(toks[1].token.text.len(), 20000*3 + 2);
+    assert_eq!((toks[2].line, toks[2].col), (20001, 3));
+}
+
+pub fn test_escseqs_numeric() {
+    let mut tstrx = TStrX::new();
+    let sstr = r"a\x41\x{1F600} b\101\0b c\u00e9\u{e9}\td";
+    // Numeric escape families are opt-in
+    let mut tstr = tstrx.from_str(sstr, true);
+    let err = tstr.nexttok(' ', true).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnknownEscSeq);
+    assert_eq!(err.ch, 'x');
+
+    tstrx.flags_mut().escapesequences_hex = true;
+    tstrx.flags_mut().escapesequences_octal = true;
+    tstrx.flags_mut().escapesequences_unicode = true;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    println!("TEST:EscSeqsNumeric:>>{}<<:>>{:#?}<<", sstr, toks);
+    let texts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    assert_eq!(texts, vec!["aA\u{1F600}", "bA\0b", "c\u{e9}\u{e9}\td"]);
+    assert_eq!(&sstr[toks[0].span.clone()], r"a\x41\x{1F600}");
+
+    let checks = [
+        (r"a\x4", ErrorKind::MalformedEscSeq, 2),
+        (r"a\x{} b", ErrorKind::MalformedEscSeq, 2),
+        (r"a\x{1234567} b", ErrorKind::MalformedEscSeq, 2),
+        (r"a\u{41 b", ErrorKind::MalformedEscSeq, 2),
+        (r"a\u12g4 b", ErrorKind::MalformedEscSeq, 2),
+        (r"a\u{110000} b", ErrorKind::InvalidCodePoint, 2),
+        (r"a\uD800 b", ErrorKind::InvalidCodePoint, 2),
+        (r"a\400 b", ErrorKind::InvalidCodePoint, 2),
+    ];
+    for (sstr, kind, chpos) in checks {
+        let mut tstr = tstrx.from_str(sstr, true);
+        let err = tstr.nexttok(' ', true).unwrap_err();
+        println!("TEST:EscSeqsNumeric:>>{}<<:>>{}<<", sstr, err);
+        assert_eq!((err.kind, err.chpos), (kind, chpos));
+    }
+    // The malformed escape sequence is dropped along with the token
+    let mut tstr = tstrx.from_str(r"a\u12g4 b", true);
+    assert!(tstr.nexttok(' ', true).is_err());
+    assert_eq!(tstr.the_str(), "g4 b");
+}
+
+pub fn test_quote_join() {
+    let mut tstrx = TStrX::new();
+    // Quoted strings cant round trip, if the quotes are retained (as by default),
+    // however values which dont require quoting are returned as is.
+    assert_eq!(tstrx.quote("a b").unwrap_err().kind, ErrorKind::Unrepresentable);
+    assert_eq!(tstrx.quote("plain").unwrap(), "plain");
+    assert_eq!(tstrx.quote("").unwrap_err().kind, ErrorKind::Unrepresentable);
+
+    tstrx.flags_mut().stringquotes_retain = false;
+    assert_eq!(tstrx.quote("a b").unwrap(), r#""a b""#);
+    assert_eq!(tstrx.quote("plain").unwrap(), "plain");
+    assert_eq!(tstrx.quote("").unwrap(), r#""""#);
+    let mut tstr = tstrx.from_str(r#""  say  " x"#, false);
+    assert_eq!(tstr.tokens_vec(' ', true, false).unwrap(), vec!["  say  ", "x"]);
+    assert_eq!(tstrx.quote("say \"hi\"\\n\n").unwrap(), r#""say \"hi\"\\n\n""#);
+
+    let values = ["a", "b c", "", "d,e", " f", "g\"h", "i(j", "k\\l", "m\tn"];
+    for dlim in [" ", ",", "::"] {
+        let line = tstrx.join(&values, dlim).unwrap();
+        println!("TEST:QuoteJoin:>>{}<<:>>{}<<", dlim, line);
+        let mut tstr = tstrx.from_str(&line, false);
+        let toks = tstr.tokens_vec(dlim, true, false).unwrap();
+        assert_eq!(toks, values);
+    }
+    assert_eq!(tstrx.join(&["a", "b c", "d(e)"], ',').unwrap(), "a,b c,d(e)");
+    let empty: [&str; 0] = [];
+    assert_eq!(tstrx.join(&empty, ",").unwrap(), "");
+
+    // Use the other quote kind, if escape sequences are not expanded
+    tstrx.flags_mut().escapesequences_expand = false;
+    tstrx.delims_mut().strings = vec!["\"".to_string(), "'".to_string()];
+    assert_eq!(tstrx.quote("say \"hi\"").unwrap(), r#"'say "hi"'"#);
+    let err = tstrx.quote("it's \"hi\"").unwrap_err();
+    assert_eq!((err.kind, err.tok.as_str()), (ErrorKind::Unrepresentable, "it's \"hi\""));
+}
+
+/// Convert the tree of tokens into a string, which shows its structure, for testing
+fn tree_str(vtree: &[TokenTree]) -> String {
+    let mut vnodes = Vec::new();
+    for node in vtree {
+        match node {
+            TokenTree::Leaf(tok) => vnodes.push(tok.text.to_string()),
+            TokenTree::String(tok) => vnodes.push(format!("S:{}", tok.text)),
+            TokenTree::Bracket { prefix, kind, children, .. } => {
+                vnodes.push(format!("B{}:{}[{}]", kind, prefix.as_deref().unwrap_or(""), tree_str(children)));
+            }
+        }
+    }
+    vnodes.join("|")
+}
+
+pub fn test_parse_tree() {
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().brackets.push(("[".to_string(), "]".to_string()));
+    tstrx.escseqs_update();
+    tstrx.escseqs_set(',', ',');
+    let sstr = r#" f(a, "b c", g[h, (i)], ()) , x\,y ,"z" "#;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let vtree = tstr.parse_tree(',', true).unwrap();
+    println!("TEST:ParseTree:>>{}<<:>>{:#?}<<", sstr, vtree);
+    assert_eq!(tree_str(&vtree), r#"B0:f[a|S:"b c"|B1:g[h|B0:[i]]|B0:[]]|x,y|S:"z""#);
+    assert_eq!(&sstr[vtree[0].token().span.clone()], r#"f(a, "b c", g[h, (i)], ())"#);
+    if let TokenTree::Bracket { children, .. } = &vtree[0] {
+        assert_eq!(&sstr[children[2].token().span.clone()], "g[h, (i)]");
+        assert_eq!(children[1].token().toktype, TokenType::String(0));
+    } else {
+        panic!("expected a bracket node");
+    }
+
+    // Errors within the blocks are reported wrt the original string
+    let sstr = r#"f(a, g("b), c)"#;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let err = tstr.parse_tree(',', true).unwrap_err();
+    assert_eq!((err.kind, err.chpos), (ErrorKind::UnterminatedString, 7));
+
+    // The tree agrees with nexttok_ex, wrt brackets not tracked (ie non strict)
+    let sstr = "f(a[b) c, g[(h) i]";
+    let mut tstr = tstrx.from_str(sstr, true);
+    let vtree = tstr.parse_tree(' ', true).unwrap();
+    assert_eq!(tree_str(&vtree), "B0:f[a[b]|c,|B1:g[B0:[h]|i]");
+    let mut tstr = tstrx.from_str(sstr, true);
+    let toks = tstr.tokens_vec(' ', true, false).unwrap();
+    let texts: Vec<&str> = vtree.iter().map(|node| node.token().text.as_ref()).collect();
+    assert_eq!(texts, toks);
+    tstrx.flags_mut().bracket_strict = true;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let err = tstr.parse_tree(' ', true).unwrap_err();
+    assert_eq!((err.kind, err.chpos), (ErrorKind::MismatchedBracket, 5));
+
+    // The text of the blocks within, with escape sequences expanded, is taken over as is
+    tstrx.flags_mut().cooked_map = true;
+    let sstr = r#"a(b[c\,d (e\tf)] "(" g\]h)"#;
+    let mut tstr = tstrx.from_str(sstr, true);
+    let vtree = tstr.parse_tree(' ', true).unwrap();
+    assert_eq!(tree_str(&vtree), "B0:a[B1:b[c,d|B0:[e\tf]]|S:\"(\"|g]h]");
+    if let TokenTree::Bracket { children, .. } = &vtree[0] {
+        let tok = children[0].token();
+        assert_eq!(tok.text, "b[c,d (e\tf)]");
+        assert_eq!(tok.rawmap.as_ref().unwrap().raw, r"b[c\,d (e\tf)]");
+        let seg = &tok.rawmap.as_ref().unwrap().segs[1];
+        assert_eq!((&tok.text[seg.cooked.clone()], &sstr[seg.raw.clone()]), (",", r"\,"));
+    } else {
+        panic!("expected a bracket node");
+    }
+    // A child which is not a block or string as a whole, is a leaf
+    tstrx.flags_mut().blocktok_dlimuser_endreqd = true;
+    let mut tstr = tstrx.from_str("f((é)x)", true);
+    let vtree = tstr.parse_tree(' ', true).unwrap();
+    assert_eq!(tree_str(&vtree), "B0:f[(é)x]");
+
+    // The tree agrees with nexttok_ex, wrt escape sequences and nested blocks
+    let mut tstrx = TStrX::new();
+    tstrx.delims_mut().brackets = vec![("(".to_string(), ")".to_string()), ("[".to_string(), "]".to_string()), ("{".to_string(), "}".to_string()), ("<%".to_string(), "%>".to_string())];
+    tstrx.escseqs_update();
+    tstrx.escseqs_set(',', ',');
+    let samples = [
+        (r"f(\\n)", ' ', r"B0:f[\n]"),
+        (r"f(\(, a)", ',', "B0:f[(|a]"),
+        (r"(\(}[{<%%>] ])", ' ', "B0:[(}[{<%%>]|]]"),
+        (r"\({é ,\\::,)", ',', r"B0:\[{é|\::]"),
+        (r#"g(a\,b, "c,(d", [e, (f\))], {}) , h"#, ',', r#"B0:g[a,b|S:"c,(d"|B1:[e|B0:[f)]]|B2:[]]|h"#),
+    ];
+    for (sstr, dlim, stree) in samples {
+        let mut tstr = tstrx.from_str(sstr, true);
+        let vtree = tstr.parse_tree(dlim, true).unwrap();
+        println!("TEST:ParseTree:Agree:>>{}<<:>>{}<<", sstr, tree_str(&vtree));
+        assert_eq!(tree_str(&vtree), stree);
+        let mut tstr = tstrx.from_str(sstr, true);
+        let toks = tstr.tokens_vec(dlim, true, false).unwrap();
+        let texts: Vec<&str> = vtree.iter().map(|node| node.token().text.as_ref()).collect();
+        assert_eq!(texts, toks);
+        // The children (untrimmed) joined back, give the text of the block token
+        let mut tstr = tstrx.from_str(sstr, true);
+        let vtree = tstr.parse_tree(dlim, false).unwrap();
+        tree_check(&vtree, &dlim.to_string(), &tstrx.delims().brackets);
+    }
+}
+
+/// Check that the children of each bracketed block node, joined back using the delimiter,
+/// give the contents of the block, as found in the text of its token
+fn tree_check(vtree: &[TokenTree], dlim: &str, brackets: &[(String, String)]) {
+    for node in vtree {
+        if let TokenTree::Bracket { token, kind, children, .. } = node {
+            let texts: Vec<&str> = children.iter().map(|child| child.token().text.as_ref()).collect();
+            let (bbegin, bend) = &brackets[*kind];
+            let contents = token.text.trim_end().strip_suffix(bend.as_str()).unwrap();
+            let contents = &contents[contents.find(bbegin.as_str()).unwrap()+bbegin.len()..];
+            // A empty child at the end is dropped
+            let joined = texts.join(dlim);
+            assert!((joined == contents) || (format!("{}{}", joined, dlim) == contents), "{} vs {}", joined, contents);
+            tree_check(children, dlim, brackets);
+        }
+    }
+}
+
+pub fn test_typed() {
+    let sstr = "0x1F -0b101 1_000 +0o17 -170_141_183_460_469_231_731_687_303_715_884_105_728 12a 300 0x -1";
+    let mut tstr = TStr::from_str(sstr, true);
+    assert_eq!(tstr.nexttok_int::<i64>(' ', true).unwrap(), 0x1F);
+    assert_eq!(tstr.nexttok_int::<i32>(' ', true).unwrap(), -5);
+    assert_eq!(tstr.nexttok_int::<u16>(' ', true).unwrap(), 1000);
+    assert_eq!(tstr.nexttok_int::<u8>(' ', true).unwrap(), 15);
+    assert_eq!(tstr.nexttok_int::<i128>(' ', true).unwrap(), i128::MIN);
+    let err = tstr.nexttok_int::<i64>(' ', true).unwrap_err();
+    println!("TEST:Typed:>>{}<<:>>{}<<", sstr, err);
+    assert_eq!((err.kind, err.tok.as_str()), (ErrorKind::InvalidNumber, "12a"));
+    assert_eq!(err.span, Some(sstr.find("12a").unwrap()..sstr.find("12a").unwrap()+3));
+    assert_eq!(err.chpos, sstr.find("12a").unwrap());
+    assert_eq!(tstr.nexttok_int::<u8>(' ', true).unwrap_err().kind, ErrorKind::NumberOutOfRange);
+    assert_eq!(tstr.nexttok_int::<u8>(' ', true).unwrap_err().kind, ErrorKind::InvalidNumber);
+    assert_eq!(tstr.nexttok_int::<u8>(' ', true).unwrap_err().kind, ErrorKind::NumberOutOfRange);
+
+    // The full range of u128 (and i128) is supported
+    let mut tstr = TStr::from_str("340282366920938463463374607431768211455 0xffffffffffffffffffffffffffffffff 340282366920938463463374607431768211456 -0 -1 -170141183460469231731687303715884105729", true);
+    assert_eq!(tstr.nexttok_int::<u128>(' ', true).unwrap(), u128::MAX);
+    assert_eq!(tstr.nexttok_int::<u128>(' ', true).unwrap(), u128::MAX);
+    assert_eq!(tstr.nexttok_int::<u128>(' ', true).unwrap_err().kind, ErrorKind::NumberOutOfRange);
+    assert_eq!(tstr.nexttok_int::<u128>(' ', true).unwrap(), 0);
+    assert_eq!(tstr.nexttok_int::<u128>(' ', true).unwrap_err().kind, ErrorKind::NumberOutOfRange);
+    assert_eq!(tstr.nexttok_int::<i128>(' ', true).unwrap_err().kind, ErrorKind::NumberOutOfRange);
+
+    let mut tstr = TStr::from_str("1_000.5, -2.5e3 ,  inf, 1__0, _1, 1_.0", true);
+    assert_eq!(tstr.nexttok_float(',', true).unwrap(), 1000.5);
+    assert_eq!(tstr.nexttok_float(',', true).unwrap(), -2500.0);
+    assert_eq!(tstr.nexttok_float(',', true).unwrap(), f64::INFINITY);
+    assert_eq!(tstr.nexttok_float(',', true).unwrap(), 10.0);
+    assert_eq!(tstr.nexttok_float(',', true).unwrap_err().kind, ErrorKind::InvalidNumber);
+    assert_eq!(tstr.nexttok_float(',', true).unwrap_err().kind, ErrorKind::InvalidNumber);
+
+    let mut tstr = TStr::from_str("True off 1 maybe 127.0.0.1 abc", true);
+    assert!(tstr.nexttok_bool(' ', true).unwrap());
+    assert!(!tstr.nexttok_bool(' ', true).unwrap());
+    assert!(tstr.nexttok_bool(' ', true).unwrap());
+    assert_eq!(tstr.nexttok_bool(' ', true).unwrap_err().kind, ErrorKind::InvalidValue);
+    let ip: std::net::Ipv4Addr = tstr.nexttok_as(' ', true).unwrap();
+    assert_eq!(ip, std::net::Ipv4Addr::new(127, 0, 0, 1));
+    let err = tstr.nexttok_as::<u32>(' ', true).unwrap_err();
+    assert_eq!((err.kind, err.span), (ErrorKind::InvalidValue, Some(27..30)));
+}
+
+pub fn test_recovery() {
+    let sstr = r#"a, "b\q c d", e f, g"#;
+    let checks = [
+        (Recovery::Abort, vec!["a"], 1),
+        (Recovery::SkipToDelim, vec!["a", "e f", "g"], 1),
+        (Recovery::SkipToEol, vec!["a"], 1),
+        (Recovery::KeepPartial, vec!["a", "\"b", "c d\"", "e f", "g"], 1),
+    ];
+    let mut tstrx = TStrX::new();
+    tstrx.flags_mut().escapesequences_expand = false;
+    let tstr = tstrx.from_str(sstr, true);
+    let (toks, errs) = tstr.clone().tokens_vec_recover(',', true, Recovery::SkipToDelim);
+    assert!(errs.is_empty());
+    assert_eq!(toks.len(), 4);
+
+    let mut tstr = TStr::from_str(sstr, true);
+    for (recovery, texts, nerrs) in checks {
+        let (toks, errs) = tstr.clone().tokens_vec_recover(',', true, recovery);
+        println!("TEST:Recovery:{:?}:>>{}<<:>>{:?}<<:>>{:?}<<", recovery, sstr, toks, errs);
+        let gtexts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+        assert_eq!(gtexts, texts);
+        assert_eq!(errs.len(), nerrs, "{:?}", recovery);
+        assert_eq!((errs[0].kind, errs[0].chpos), (ErrorKind::UnknownEscSeq, 6));
+    }
+    let (toks, _) = tstr.tokens_vec_recover(',', true, Recovery::KeepPartial);
+    assert_eq!(&sstr[toks[1].span.clone()], "\"b\\q");
+    assert_eq!(toks[1].toktype, TokenType::Unknown);
+    // The partial token is mapped to the original string, if cooked_map
+    let sstr = r"a, b\tc\q, d";
+    let mut tstrx = TStrX::new();
+    tstrx.flags_mut().cooked_map = true;
+    let (toks, errs) = tstrx.from_str(sstr, true).tokens_vec_recover(',', true, Recovery::KeepPartial);
+    assert_eq!((errs.len(), errs[0].kind), (1, ErrorKind::UnknownEscSeq));
+    assert_eq!(toks[1].text, "b\tc");
+    let rawmap = toks[1].rawmap.as_ref().unwrap();
+    assert_eq!(rawmap.raw, r"b\tc\q");
+    let runs: Vec<(&str, &str)> = rawmap.segs.iter().map(|seg| (&toks[1].text[seg.cooked.clone()], &sstr[seg.raw.clone()])).collect();
+    assert_eq!(runs, vec![("b", "b"), ("\t", r"\t"), ("c", "c")]);
+
+    // The delimiters within a string or bracket block, open at the error, are skipped
+    for sstr in [r#"a, "b\q, c", d"#, r#"a, f(b\q, "c)", (c, \q)), d"#, r#"a, "b\q, \r"#] {
+        let (toks, errs) = TStr::from_str(sstr, true).tokens_vec_recover(',', true, Recovery::SkipToDelim);
+        let gtexts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+        let texts = if sstr.ends_with('r') { vec!["a"] } else { vec!["a", "d"] };
+        assert_eq!(gtexts, texts);
+        assert_eq!((errs.len(), errs[0].kind), (1, ErrorKind::UnknownEscSeq));
+    }
+
+    let sstr = "x \"b\\q c\ny z";
+    let mut tstr = TStr::from_str(sstr, true);
+    let (toks, errs) = tstr.tokens_vec_recover(' ', true, Recovery::SkipToEol);
+    let gtexts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    assert_eq!(gtexts, vec!["x", "y", "z"]);
+    assert_eq!(errs.len(), 1);
+}
+
+pub fn test_diag() {
+    let sstr = "a \"b\\q c\"";
+    let err = TStr::from_str(sstr, true).tokens_vec(' ', true, false).unwrap_err();
+    let report = diag::render(sstr, &err, Style::Plain);
+    println!("TEST:Diag:>>{}<<:\n{}", sstr, report);
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[0], "error: unknown escape sequence [q]");
+    assert_eq!(lines[1], " --> line 1, col 6");
+    assert_eq!(lines[3], "1 | a \"b\\q c\"");
+    assert_eq!(lines[4], "  |      ^");
+    assert!(lines[5].starts_with("  = help: "));
+
+    // Block opened on a earlier line, with tabs and a token span
+    let sstr = "x\n\t(a [b) c";
+    let mut tstr = TStr::from_str(sstr, true);
+    tstr.config_update(|x| {
+        x.delims_mut().brackets.push(("[".to_string(), "]".to_string()));
+        x.flags_mut().bracket_strict = true;
+    });
+    let err = tstr.tokens_vec(' ', true, false).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::MismatchedBracket);
+    let report = diag::render(sstr, &err, Style::Plain);
+    println!("TEST:Diag:>>{}<<:\n{}", sstr, report);
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[1], " --> line 2, col 7");
+    assert_eq!(lines[4], "  | \t   - ^");
+    assert!(!lines[5].contains("opened at line"));
+    let mut err = err.clone();
+    err.span = Some(2..6);
+    err.blockpos = Some(0);
+    let report = diag::render(sstr, &err, Style::Plain);
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[4], "  | ~~~~  ^");
+    assert_eq!(lines[5], "  = note: the block was opened at line 1, col 1");
+
+    let report = diag::render(sstr, &err, Style::Ansi);
+    println!("TEST:Diag:Ansi:\n{}", report);
+    assert!(report.starts_with("\x1b[1;31merror\x1b[0m: "));
+    assert_eq!(report.lines().count(), 7);
+}
+
+pub fn test_peek_rewind() {
+    let sstr = "  let x = (1 + 2) ";
+    let mut tstr = TStr::from_str(sstr, true);
+    assert_eq!(tstr.original(), sstr);
+    assert_eq!(tstr.consumed(), 2);
+    let tok = tstr.peek_tok(' ', true).unwrap();
+    assert_eq!(tok.text, "let");
+    assert_eq!(tstr.consumed(), 2);
+    assert_eq!(tstr.nexttok(' ', true).unwrap(), "let");
+    let cp = tstr.checkpoint();
+    let pos = tstr.consumed();
+    let toks = tstr.tokens_vec(' ', true, false).unwrap();
+    assert_eq!(toks, vec!["x", "=", "(1 + 2)"]);
+    assert_eq!(tstr.remaining_len(), 0);
+    assert_eq!(tstr.consumed(), sstr.trim_end().len());
+    tstr.rewind(cp);
+    assert_eq!(tstr.consumed(), pos);
+    assert_eq!(tstr.the_str(), "x = (1 + 2)");
+    assert_eq!(tstr.trimmed_suffix_cnt(), 1);
+    assert_eq!(tstr.peek_tok(' ', true).unwrap().span, 6..7);
+    assert_eq!(tstr.nexttok(' ', true).unwrap(), "x");
+    println!("TEST:PeekRewind:>>{}<<:>>{}<<:{}", tstr.original(), tstr, tstr.consumed());
+
+    // A peek which errors, also leaves the position as is
+    let mut tstr = TStr::from_str(r#"a "b"#, true);
+    assert_eq!(tstr.nexttok(' ', true).unwrap(), "a");
+    assert_eq!(tstr.peek_tok(' ', true).unwrap_err().kind, ErrorKind::UnterminatedString);
+    assert_eq!(tstr.the_str(), r#""b"#);
+}
+
+pub fn test_location() {
+    let sstr = "a b\r\n\tc \"d\n\tॐe\" f\n\n  (g";
+    let mut tstr = TStr::from_str(sstr, true);
+    let lmap = tstr.line_map();
+    assert_eq!(lmap.lines(), 5);
+    assert_eq!(lmap.line(1), Some("a b"));
+    assert_eq!(lmap.line(3), Some("\tॐe\" f"));
+    assert_eq!(lmap.line(4), Some(""));
+    assert_eq!(lmap.line(6), None);
+    assert_eq!(lmap.line(0), None);
+    assert_eq!(lmap.line_span(2), Some(5..10));
+
+    let toks = tstr.tokens_vec_ex(' ', true, true).unwrap();
+    let texts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    println!("TEST:Location:>>{:?}<<:>>{:?}<<", sstr, texts);
+    assert_eq!(texts, vec!["a", "b\r\n\tc", "\"d\n\tॐe\"", "f"]);
+    let (sloc, eloc) = lmap.span(&toks[2].span);
+    assert_eq!(sloc, Location { line: 2, col_byte: 4, col_char: 4, col_tab: 11 });
+    assert_eq!(eloc, Location { line: 3, col_byte: 7, col_char: 5, col_tab: 12 });
+    assert_eq!(lmap.location(toks[3].span.start), Location { line: 3, col_byte: 8, col_char: 6, col_tab: 13 });
+    // A offset in the middle of a char, and beyond the end
+    assert_eq!(lmap.location(toks[2].span.start + 4).col_byte, 2);
+    assert_eq!(lmap.location(1000), Location { line: 5, col_byte: 5, col_char: 5, col_tab: 5 });
+
+    let mut tstr = TStr::from_str(sstr, true);
+    let err = tstr.tokens_vec(' ', true, false).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnterminatedBracket);
+    let mut lmap = tstr.line_map();
+    lmap.tabwidth = 4;
+    assert_eq!(lmap.location(err.chpos), Location { line: 5, col_byte: 3, col_char: 3, col_tab: 3 });
+    assert_eq!(lmap.location(sstr.find('c').unwrap()).col_tab, 5);
+}
+
+pub fn test_cooked_map() {
+    let sstr = r#"ab  "c\td\u{e9}ॐ\"" x\ny  "#;
+    let mut tstrx = TStrX::new();
+    tstrx.flags_mut().stringquotes_retain = false;
+    tstrx.flags_mut().escapesequences_unicode = true;
+    let mut tstr = tstrx.from_str(sstr, false);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    assert!(toks.iter().all(|tok| tok.rawmap.is_none()));
+    assert_eq!(toks[1].raw_range(0), None);
+
+    tstrx.flags_mut().cooked_map = true;
+    let mut tstr = tstrx.from_str(sstr, false);
+    let toks = tstr.tokens_vec_ex(' ', true, false).unwrap();
+    println!("TEST:CookedMap:>>{}<<:>>{:#?}<<", sstr, toks);
+    let texts: Vec<&str> = toks.iter().map(|tok| tok.text.as_ref()).collect();
+    assert_eq!(texts, vec!["ab", "c\tdéॐ\"", "x\ny"]);
+    // Borrowed token, mapping 1 to 1
+    let rawmap = toks[0].rawmap.as_ref().unwrap();
+    assert_eq!(rawmap.raw, "ab");
+    assert_eq!(rawmap.segs.len(), 1);
+    assert_eq!(toks[0].raw_range(1), Some(1..2));
+    // Cooked string token, with quotes stripped and escapes expanded
+    let tok = &toks[1];
+    let rawmap = tok.rawmap.as_ref().unwrap();
+    assert_eq!(rawmap.raw, r#""c\td\u{e9}ॐ\"""#);
+    assert_eq!(&sstr[tok.span.clone()], rawmap.raw);
+    let raws: Vec<&str> = (0..tok.text.chars().count()).map(|i| &sstr[tok.raw_range(i).unwrap()]).collect();
+    assert_eq!(raws, vec!["c", "\\t", "d", "\\u{e9}", "ॐ", "\\\""]);
+    assert_eq!(tok.raw_range(6), None);
+    assert_eq!(rawmap.segs.iter().filter(|seg| seg.is_literal()).count(), 3);
+    // Escape within a normal token, including the raw range of a multi byte char
+    assert_eq!(toks[2].raw_range(1).map(|r| &sstr[r]), Some("\\n"));
+    assert_eq!(toks[1].raw_range(4), Some(15..18));
+
+    // Trimming at end wrt a non space delimiter, adjusts the runs
+    let sstr = r#"  a\tb  , c d\t  "#;
+    let mut tstr = tstrx.from_str(sstr, false);
+    let toks = tstr.tokens_vec_ex(',', true, false).unwrap();
+    assert_eq!(toks[0].text, "a\tb");
+    assert_eq!(toks[0].raw_range(0), Some(2..3));
+    assert_eq!(toks[0].raw_range(2).map(|r| &sstr[r]), Some("b"));
+    assert_eq!(toks[1].text, "c d");
+    assert_eq!(toks[1].rawmap.as_ref().unwrap().segs.len(), 1);
+    assert_eq!(toks[1].raw_range(2).map(|r| &sstr[r]), Some("d"));
+
+    // The rest of the string returned by splitn_ex
+    let mut tstr = tstrx.from_str("a b\\tc", true);
+    let toks = tstr.splitn_ex(2, ' ').unwrap();
+    assert_eq!(toks[1].raw_range(2), Some(4..5));
+}
+
+pub fn test_tstring() {
+    // A session object, which keeps the remaining input, beyond the line buffer
+    struct Session {
+        pending: Option<TString>,
+    }
+    let mut session = Session { pending: None };
+    {
+        let line = String::from(r#"  cmd "a b\tc" f(1, 2) 42 rest of it "#);
+        let mut tstr = TStr::from_str(&line, true);
+        assert_eq!(tstr.nexttok(' ', true).unwrap(), "cmd");
+        session.pending = Some(TString::from(&tstr));
+    }
+    let mut tstring = session.pending.take().unwrap();
+    assert_eq!(tstring.the_str(), r#""a b\tc" f(1, 2) 42 rest of it"#);
+    assert_eq!(tstring.consumed(), 6);
+    let tok = tstring.peek_tok(' ', true).unwrap();
+    assert_eq!(tok.text, "\"a b\tc\"");
+    assert_eq!(tok.span, 6..14);
+    assert_eq!(tstring.nexttok_ex(' ', true).unwrap(), tok);
+    let cp = tstring.checkpoint();
+    assert_eq!(tstring.nexttok(' ', true).unwrap(), "f(1, 2)");
+    assert_eq!(tstring.nexttok_as::<u32>(' ', true).unwrap(), 42);
+    // Continue on another thread, with the remaining input
+    let handle = std::thread::spawn(move || {
+        let toks = tstring.tokens_vec(' ', true, false).unwrap();
+        (tstring, toks)
+    });
+    let (mut tstring, toks) = handle.join().unwrap();
+    assert_eq!(toks, vec!["rest", "of", "it"]);
+    assert_eq!(tstring.remaining_len(), 0);
+    tstring.rewind(cp);
+    assert_eq!(tstring.to_string(), "f(1, 2) 42 rest of it");
+    assert_eq!(tstring.splitn(2, ' ').unwrap(), vec!["f(1, 2)", "42 rest of it"]);
+    tstring.rewind(cp);
+    // Back to a TStr, which borrows from the TString, and the other way
+    let mut tstr = tstring.as_tstr();
+    assert_eq!(tstr.original(), tstring.original().as_ref());
+    assert_eq!(tstr.nexttok(' ', true).unwrap(), "f(1, 2)");
+    let tstring2 = TString::from(tstr);
+    assert_eq!(tstring2.the_str(), "42 rest of it");
+    assert_eq!(tstring.the_str(), "f(1, 2) 42 rest of it");
+    let block = tstring.with_tstr(|tstr| {
+        tstr.drop_adjust(0);
+        tstr.nexttok_ex(' ', true).map(|tok| tok.span)
+    });
+    assert_eq!(block.unwrap(), 15..22);
+    assert_eq!(tstring.the_str(), "42 rest of it");
+
+    // Created through TStrX, with its characteristics
+    let mut tstrx = TStrX::new();
+    tstrx.flags_mut().stringquotes_retain = false;
+    let mut tstring = tstrx.from_string(String::from(r#" "x y", z "#), true);
+    assert_eq!(tstring.tokens_vec(',', true, false).unwrap(), vec!["x y", "z"]);
+    println!("TEST:TString:{:?}", tstring);
+
+    // The typed, tree and peel methods, which return owned results
+    let mut tstring = TString::from_string("0x10 2.5 on f(a, [b]) x", true);
+    assert_eq!(tstring.nexttok_int::<u8>(' ', true).unwrap(), 16);
+    assert_eq!(tstring.nexttok_float(' ', true).unwrap(), 2.5);
+    assert!(tstring.nexttok_bool(' ', true).unwrap());
+    let vtree = tstring.parse_tree(' ', true).unwrap();
+    assert_eq!(tree_str(&vtree), "B0:f[a,|[b]]|x");
+    let mut tstring = TString::from_string(r#" tag(1, "2") "#, true);
+    let mut texts = Vec::new();
+    for tok in tstring.clone().tokens(',', true) {
+        texts.push(tok.unwrap().text.into_owned());
+    }
+    assert_eq!(texts, vec![r#"tag(1, "2")"#]);
+    let block = tstring.peel_bracket(None).unwrap();
+    assert_eq!((block.prefix.as_ref(), block.contents.as_ref()), ("tag", r#"1, "2""#));
+    assert_eq!(tstring.nexttok(',', true).unwrap(), "1");
+    assert_eq!(tstring.peel_string(None).unwrap(), "2");
+}
+
+pub fn test_config_shared() {
+    fn is_send_sync<T: Send + Sync>(_: &T) {}
+    let mut tstrx = TStrX::new();
+    tstrx.flags_mut().stringquotes_retain = false;
+    let tstr1 = tstrx.from_str(r#"a "b c""#, true);
+    let tstr2 = tstrx.from_str("d e", true);
+    assert!(Arc::ptr_eq(tstr1.config(), tstr2.config()));
+    assert!(Arc::ptr_eq(tstr1.config(), &tstrx.config()));
+    assert!(Arc::ptr_eq(TStr::from_str("x", true).config(), &TConfig::shared_default()));
+    is_send_sync(tstr1.config());
+
+    // Changing the characteristics of the TStrX, gives a new config
+    tstrx.flags_mut().stringquotes_retain = true;
+    let tstr3 = tstrx.from_str(r#"a "b c""#, true);
+    assert!(!Arc::ptr_eq(tstr1.config(), tstr3.config()));
+    assert!(tstr3.config().flags().stringquotes_retain);
+    tstrx.escseqs_set('q', 'Q');
+    assert!(!Arc::ptr_eq(tstr3.config(), &tstrx.config()));
+    assert_eq!(tstrx.config().escseqs().get(&'q'), Some(&'Q'));
+
+    // Updating the config of a TStr, doesnt affect the others sharing it
+    let mut tstr1 = tstr1;
+    tstr1.config_update(|x| x.flags_mut().stringquotes_retain = true);
+    assert_eq!(tstr1.clone().tokens_vec(' ', true, false).unwrap(), vec!["a", "\"b c\""]);
+    let mut tstr4 = tstr2.config().from_str(r#"a "b c""#, true);
+    assert_eq!(tstr4.tokens_vec(' ', true, false).unwrap(), vec!["a", "b c"]);
+    // The uncompiled chain (used as the baseline by the bench), gives the same tokens
+    #[cfg(feature = "bench")]
+    {
+        let sstr = r#"a\tb, "c, \"d", f(g, "(", h) , j"#;
+        let tstrx = TStrX::new();
+        let toks = tstrx.from_str(sstr, true).tokens_vec_ex(',', true, false).unwrap();
+        assert_eq!(tstrx.config().uncompiled().from_str(sstr, true).tokens_vec_ex(',', true, false).unwrap(), toks);
+    }
+
+    // Share a config across threads
+    let cfg = tstr2.config().clone();
+    let handles: Vec<_> = (0..4).map(|i| {
+        let cfg = cfg.clone();
+        std::thread::spawn(move || {
+            let line = format!(r#"{} "x y" z"#, i);
+            let mut tstr = cfg.from_str(&line, true);
+            tstr.tokens_vec(' ', true, false).unwrap().into_iter().map(|tok| tok.into_owned()).collect::<Vec<String>>()
+        })
+    }).collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), vec![i.to_string(), "x y".to_string(), "z".to_string()]);
+    }
+    println!("TEST:ConfigShared:{:?}", cfg);
+}
@@ -0,0 +1,291 @@
+//!
+//! Tree - Tokenise a line into a tree of tokens, as defined by its bracketed blocks
+//! HanishKVC, 2022
+//!
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use crate::{TStr, Token, TokenType, Error, DelimDef, CookedSeg, RawMap};
+use crate::nexttoken::{BracketMark, MarkKind};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A node in the tree of tokens, returned by TStr::parse_tree
+pub enum TokenTree<'a> {
+    /// A normal token (or a comment, if comments are retained)
+    Leaf(Token<'a>),
+    /// A string token
+    String(Token<'a>),
+    /// A bracketed block token
+    Bracket {
+        /// The whole of the bracketed block token, including its prefix if any
+        token: Token<'a>,
+        /// The textual prefix before the opening bracket, if any
+        prefix: Option<Cow<'a, str>>,
+        /// The index of the bracket type (wrt Delimiters::brackets), which opened the block
+        kind: usize,
+        /// The tokens within the bracketed block
+        children: Vec<TokenTree<'a>>,
+    },
+}
+
+impl<'a> TokenTree<'a> {
+
+    /// The token corresponding to this node
+    pub fn token(&self) -> &Token<'a> {
+        match self {
+            TokenTree::Leaf(token) => token,
+            TokenTree::String(token) => token,
+            TokenTree::Bracket { token, .. } => token,
+        }
+    }
+
+    /// Convert into a node (along with its children), which doesnt borrow from the original string
+    pub fn into_owned(self) -> TokenTree<'static> {
+        match self {
+            TokenTree::Leaf(token) => TokenTree::Leaf(token.into_owned()),
+            TokenTree::String(token) => TokenTree::String(token.into_owned()),
+            TokenTree::Bracket { token, prefix, kind, children } => TokenTree::Bracket {
+                token: token.into_owned(),
+                prefix: prefix.map(|prefix| Cow::Owned(prefix.into_owned())),
+                kind,
+                children: children.into_iter().map(TokenTree::into_owned).collect(),
+            },
+        }
+    }
+
+}
+
+
+/// A bracket block within a bracket block token, along with the delimiters and strings
+/// directly within it (ie not within its child blocks), and the blocks within it.
+/// The positions are wrt the original string, while tlstart/tlend (ie the length of the
+/// token text before its begin/end markers) are wrt the text of the bracket block token.
+struct Block {
+    bid: usize,
+    start: usize,
+    cpos: usize,
+    end: usize,
+    tlstart: usize,
+    tlend: usize,
+    delims: Vec<BracketMark>,
+    /// The string type, along with the byte positions of the begin quote and just after the end quote
+    strings: Vec<(usize, usize, usize)>,
+    children: Vec<Block>,
+}
+
+impl Block {
+
+    ///
+    /// Work out the bracket block (and the blocks within it), from the markers recorded
+    /// while scanning a bracket block token.
+    ///
+    /// The brackets of the type, which opened the token, are balanced as counted by the
+    /// tokeniser. Other types of brackets are blocks only if they are closed before the
+    /// enclosing block, else (which is possible if bracket_strict is not set) they are
+    /// left as normal chars, with the delimiters and strings within them belonging to
+    /// the enclosing block.
+    ///
+    fn from_marks(marks: &[BracketMark]) -> Option<Block> {
+        let mut stack: Vec<Block> = Vec::new();
+        let mut string = None;
+        for mark in marks {
+            let bid = match mark.kind {
+                MarkKind::BracketBegin(bid) => {
+                    stack.push(Block { bid, start: mark.start, cpos: 0, end: 0, tlstart: mark.toklen, tlend: 0, delims: Vec::new(), strings: Vec::new(), children: Vec::new() });
+                    continue;
+                }
+                MarkKind::Delim => {
+                    if let Some(block) = stack.last_mut() {
+                        block.delims.push(mark.clone());
+                    }
+                    continue;
+                }
+                MarkKind::StringBegin(sid) => {
+                    string = Some((sid, mark.start));
+                    continue;
+                }
+                MarkKind::StringEnd => {
+                    if let (Some((sid, start)), Some(block)) = (string.take(), stack.last_mut()) {
+                        block.strings.push((sid, start, mark.end));
+                    }
+                    continue;
+                }
+                MarkKind::BracketEnd(bid) => bid,
+            };
+            let found = match stack.first() {
+                Some(main) if main.bid == bid => stack.iter().rposition(|block| block.bid == bid),
+                Some(_) if stack.last().is_some_and(|block| block.bid == bid) => Some(stack.len()-1),
+                _ => None,
+            };
+            let Some(i) = found else {
+                continue;
+            };
+            while stack.len() > i+1 {
+                let unclosed = stack.pop().unwrap();
+                let parent = stack.last_mut().unwrap();
+                parent.delims.extend(unclosed.delims);
+                parent.strings.extend(unclosed.strings);
+                parent.children.extend(unclosed.children);
+            }
+            let mut block = stack.pop().unwrap();
+            (block.cpos, block.end, block.tlend) = (mark.start, mark.end, mark.toklen);
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(block),
+                None => return Some(block),
+            }
+        }
+        None
+    }
+
+}
+
+
+/// The text of a bracket block token, along with the runs making it up, if cooked_map
+struct BlockText<'t> {
+    text: &'t str,
+    segs: Option<&'t [CookedSeg]>,
+}
+
+
+/// Tree of tokens related methods
+impl<'a> TStr<'a> {
+
+    /// Convert the given token into a node of the tree, inturn splitting the contents of
+    /// its bracket block (if any) into its children.
+    fn tree_node(&self, token: Token<'a>, block: Option<&Block>, dlimdef: &str, btrim: bool) -> TokenTree<'a> {
+        let block = match (&token.toktype, block) {
+            (TokenType::String(_), _) => return TokenTree::String(token),
+            (TokenType::BracketStandalone | TokenType::BracketPrefixed, Some(block)) => block,
+            _ => return TokenTree::Leaf(token),
+        };
+        let btext = BlockText { text: &token.text, segs: token.rawmap.as_ref().map(|rawmap| &rawmap.segs[..]) };
+        let children = self.tree_children(block, &btext, dlimdef, btrim);
+        let prefix = if block.start > token.span.start { Some(Cow::Borrowed(&self.theOrig[token.span.start..block.start])) } else { None };
+        TokenTree::Bracket { token, prefix, kind: block.bid, children }
+    }
+
+    ///
+    /// Split the contents of the given bracket block into its child tokens (and so on
+    /// recursively), at the delimiters directly within it, as noted by the tokeniser.
+    /// The text of each child is the corresponding part of the text of the bracket
+    /// block token.
+    ///
+    /// A empty (or only spaces, if btrim is set) child at the end is dropped, as are
+    /// such children between consecutive spaces, if the delimiter is the space delimiter.
+    ///
+    fn tree_children(&self, block: &Block, btext: &BlockText, dlimdef: &str, btrim: bool) -> Vec<TokenTree<'a>> {
+        let (bbegin, _) = &self.cfg.delims.brackets[block.bid];
+        let space = self.cfg.delims.space;
+        let bdlimspace = (dlimdef.len() == space.len_utf8()) && dlimdef.starts_with(space);
+        let (mut rstart, mut cstart) = (block.start + bbegin.len(), block.tlstart + bbegin.len());
+        let mut delims = block.delims.iter();
+        let mut vtree = Vec::new();
+        loop {
+            let (rend, cend, rnext, blast) = match delims.next() {
+                Some(mark) => (mark.start, mark.toklen, mark.end, false),
+                None => (block.cpos, block.tlend, block.cpos, true),
+            };
+            let raw = &self.theOrig[rstart..rend];
+            let bempty = raw.is_empty() || (btrim && raw.trim().is_empty());
+            if !bempty || !(blast || bdlimspace) {
+                vtree.push(self.tree_child(block, btext, rstart..rend, cstart..cend, dlimdef, btrim));
+            }
+            if blast {
+                break;
+            }
+            (rstart, cstart) = (rnext, cend + (rnext - rend));
+        }
+        vtree
+    }
+
+    /// Convert the given part of the contents of the given bracket block into a child token
+    fn tree_child(&self, block: &Block, btext: &BlockText, raw: Range<usize>, cooked: Range<usize>, dlimdef: &str, btrim: bool) -> TokenTree<'a> {
+        let (mut tstart, mut tend) = (raw.start, raw.end);
+        let (mut cstart, mut cend) = (cooked.start, cooked.end);
+        if btrim {
+            let (sraw, stext) = (&self.theOrig[tstart..tend], &btext.text[cstart..cend]);
+            tstart += sraw.len() - sraw.trim_start().len();
+            cstart += stext.len() - stext.trim_start().len();
+            if self.cfg.flags.trim_atend {
+                tend = tstart + sraw.trim().len();
+                cend = cstart + stext.trim().len();
+            }
+        }
+        let cbegin = block.start + self.cfg.delims.brackets[block.bid].0.len();
+        let lstart = cbegin + self.theOrig[cbegin..tstart].trim_end().len();
+        let tail = &self.theOrig[tend..block.cpos];
+        let trail = tend..tend + tail.len() - tail.trim_start().len();
+        let i = block.children.partition_point(|cblock| cblock.start < tstart);
+        let cblock = block.children.get(i).filter(|cblock| (cblock.start < tend) && (cblock.end == tend));
+        let string = block.strings.iter().find(|(_, start, end)| (*start == tstart) && (*end == tend));
+        let toktype = match (cblock, string) {
+            (Some(cblock), _) if cblock.start > tstart => TokenType::BracketPrefixed,
+            (Some(_), _) => TokenType::BracketStandalone,
+            (None, Some((sid, _, _))) => {
+                if !self.cfg.flags.stringquotes_retain {
+                    let qlen = self.cfg.delims.strings[*sid].len();
+                    (cstart, cend) = (cstart + qlen, (cend - qlen).max(cstart + qlen));
+                }
+                TokenType::String(*sid)
+            }
+            (None, None) if cstart == cend => TokenType::Unknown,
+            (None, None) => TokenType::Normal,
+        };
+        let text = &btext.text[cstart..cend];
+        let text = if text == &self.theOrig[tstart..tend] { Cow::Borrowed(&self.theOrig[tstart..tend]) } else { Cow::Owned(text.to_string()) };
+        let rawmap = btext.segs.map(|segs| RawMap {
+            raw: Cow::Borrowed(&self.theOrig[tstart..tend]),
+            segs: CookedSeg::clip(segs, cstart..cend, 0),
+        });
+        let token = Token { text, toktype, span: tstart..tend, lead: lstart..tstart, trail, rawmap };
+        match (cblock, string) {
+            (Some(cblock), _) => {
+                let children = self.tree_children(cblock, btext, dlimdef, btrim);
+                let prefix = if cblock.start > tstart { Some(Cow::Borrowed(&self.theOrig[tstart..cblock.start])) } else { None };
+                TokenTree::Bracket { token, prefix, kind: cblock.bid, children }
+            }
+            (None, Some(_)) => TokenTree::String(token),
+            (None, None) => TokenTree::Leaf(token),
+        }
+    }
+
+    ///
+    /// Get the tree of tokens in the current string/line, ie the tokens in it, with
+    /// the contents of any bracketed block tokens inturn split into their child tokens,
+    /// using the same delimiter, and so on recursively.
+    ///
+    /// The bracketed blocks (and the blocks, delimiters and strings within them) are
+    /// identified by the tokeniser, as it scans each token, and the text of each child
+    /// token is the corresponding part of the text of the bracketed block token. So the
+    /// tree agrees with the tokens returned by nexttok_ex, including wrt escape sequences
+    /// and comments (which are retained as is) within the blocks, and each char is
+    /// scanned only once, whatever the depth.
+    ///
+    /// If bracket_strict is not set, a bracket type other than the one which opened the
+    /// enclosing block, is a block only if it is closed within the enclosing block, else
+    /// it is treated as a normal char.
+    ///
+    /// A child token is a bracketed block node, only if it ends with the (only) block
+    /// in it, and a string node, only if it is a string as a whole. Any other child
+    /// (say a(b)c) is a leaf, even if it contains blocks or strings.
+    ///
+    /// The tokens at all levels have their spans wrt the original string. Any error
+    /// found is returned like how nexttok_ex would have returned it.
+    ///
+    pub fn parse_tree(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<Vec<TokenTree<'a>>, Error> {
+        let mut dlimbuf = [0; 4];
+        let dlimdef = dlimdef.dlim_str(&mut dlimbuf);
+        let mut vtree = Vec::new();
+        while self.remaining_len() > 0 {
+            let mut marks = Vec::new();
+            if let Some(token) = self.nexttok_tree(dlimdef, btrim, &mut marks, None)? {
+                let block = Block::from_marks(&marks);
+                vtree.push(self.tree_node(token, block.as_ref(), dlimdef, btrim));
+            }
+        }
+        Ok(vtree)
+    }
+
+}
@@ -0,0 +1,328 @@
+//!
+//! TString - A TStr, which owns the string being tokenised
+//! HanishKVC, 2022
+//!
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::FusedIterator;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::{TStr, TConfig, Token, TokenTree, BracketBlock, Error, Delimiters, Flags, Checkpoint, Recovery, DelimDef};
+
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone)]
+///
+/// The owning counterpart of TStr, which holds the string being tokenised
+/// (in a Arc<str>), instead of borrowing it. So it can be kept around (with
+/// its remaining input) beyond the string/line buffer from which it was
+/// created, or sent to another thread.
+///
+/// It supports the same tokenising methods as TStr, except that the returned
+/// tokens own their text. The remaining methods of TStr can be used through
+/// with_tstr.
+///
+/// Cloning a TString shares the underlying string, as well as the config.
+///
+pub struct TString {
+    /// The original string given to this TString
+    theOrig: Arc<str>,
+    /// The byte range of the original string, which is remaining to be tokenised
+    start: usize,
+    end: usize,
+    /// The amount of space trimmed at the begining and end of the string
+    trimmedPrefixCnt: isize,
+    trimmedSuffixCnt: isize,
+    /// The tokenisation characteristics (delims, escseqs, flags), shared with others
+    cfg: Arc<TConfig>,
+}
+
+impl TString {
+
+    ///
+    /// Create a new instance of TString for the given string,
+    /// using the passed delimiters, escape sequences and flags.
+    ///
+    /// If btrim is set, then trim the string
+    ///
+    pub fn from_string_ex(s: impl Into<Arc<str>>, btrim: bool, delims: Delimiters, escseqs: HashMap<char, char>, flags: Flags) -> TString {
+        Self::from_string_cfg(s, btrim, Arc::new(TConfig::new(delims, escseqs, flags)))
+    }
+
+    ///
+    /// Create a new instance of TString for the given string,
+    /// which uses (shares) the given config.
+    ///
+    /// If btrim is set, then trim the string
+    ///
+    pub fn from_string_cfg(s: impl Into<Arc<str>>, btrim: bool, cfg: Arc<TConfig>) -> TString {
+        let orig: Arc<str> = s.into();
+        let mut tstring = TString {
+            end: orig.len(),
+            theOrig: orig,
+            start: 0,
+            trimmedPrefixCnt: -1,
+            trimmedSuffixCnt: -1,
+            cfg,
+        };
+        if btrim {
+            tstring.trim();
+        }
+        tstring
+    }
+
+    /// Create a new instance of TString from the given string.
+    /// Setup the delimiters, esc sequences and flags to their defaults.
+    ///
+    /// If btrim is set, then trim the string
+    pub fn from_string(s: impl Into<Arc<str>>, btrim: bool) -> TString {
+        Self::from_string_cfg(s, btrim, TConfig::shared_default())
+    }
+
+    /// The config (tokenisation characteristics) used by this TString
+    pub fn config(&self) -> &Arc<TConfig> {
+        &self.cfg
+    }
+
+    /// Switch this TString to use the given config
+    pub fn set_config(&mut self, cfg: Arc<TConfig>) {
+        self.cfg = cfg;
+    }
+
+    ///
+    /// Work with a TStr over the remaining string, which inturn allows any of the
+    /// methods of TStr to be used. What the TStr consumes, is consumed from this
+    /// TString, once the given function returns.
+    ///
+    pub fn with_tstr<R>(&mut self, f: impl FnOnce(&mut TStr<'_>) -> R) -> R {
+        let mut tstr = TStr {
+            theOrig: &self.theOrig,
+            theStr: &self.theOrig[self.start..self.end],
+            trimmedPrefixCnt: self.trimmedPrefixCnt,
+            trimmedSuffixCnt: self.trimmedSuffixCnt,
+            cfg: self.cfg.clone(),
+        };
+        let res = f(&mut tstr);
+        let start = tstr.orig_offset();
+        let end = start + tstr.theStr.len();
+        let (prefixcnt, suffixcnt) = (tstr.trimmedPrefixCnt, tstr.trimmedSuffixCnt);
+        (self.start, self.end) = (start, end);
+        (self.trimmedPrefixCnt, self.trimmedSuffixCnt) = (prefixcnt, suffixcnt);
+        res
+    }
+
+    /// Get a TStr, which borrows the string from this TString, and is at the same
+    /// position with the same characteristics.
+    pub fn as_tstr(&self) -> TStr<'_> {
+        TStr {
+            theOrig: &self.theOrig,
+            theStr: &self.theOrig[self.start..self.end],
+            trimmedPrefixCnt: self.trimmedPrefixCnt,
+            trimmedSuffixCnt: self.trimmedSuffixCnt,
+            cfg: self.cfg.clone(),
+        }
+    }
+
+    /// retrieve the string remaining to be tokenised, as it stands currently
+    pub fn the_str(&self) -> &str {
+        &self.theOrig[self.start..self.end]
+    }
+
+    /// The original string given to this TString
+    pub fn original(&self) -> &Arc<str> {
+        &self.theOrig
+    }
+
+    /// The byte offset wrt the original string, till where it has been consumed till now.
+    pub fn consumed(&self) -> usize {
+        self.start
+    }
+
+    /// Return remaining text len wrt the current string, which is not yet tokenised/extracted
+    pub fn remaining_len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Trim any space at begin or end of the remaining string.
+    pub fn trim(&mut self) {
+        self.with_tstr(|tstr| tstr.trim())
+    }
+
+    /// Save the current position, so that one can rewind to it later.
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.as_tstr().checkpoint()
+    }
+
+    /// Restore the position saved in the given checkpoint.
+    pub fn rewind(&mut self, cp: Checkpoint) {
+        self.with_tstr(|tstr| tstr.rewind(cp))
+    }
+
+    /// Drop text till specified NextTokPos
+    pub fn drop_adjust(&mut self, nexttokpos: usize) {
+        self.with_tstr(|tstr| tstr.drop_adjust(nexttokpos))
+    }
+
+    /// NOTE: Look at the doc related to TStr::nexttok_ex for the details.
+    pub fn nexttok_ex(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<Token<'static>, Error> {
+        self.with_tstr(|tstr| tstr.nexttok_ex(dlimdef, btrim).map(Token::into_owned))
+    }
+
+    /// NOTE: Look at the doc related to TStr::nexttok for the details.
+    pub fn nexttok(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<String, Error> {
+        self.with_tstr(|tstr| tstr.nexttok(dlimdef, btrim).map(Cow::into_owned))
+    }
+
+    /// NOTE: Look at the doc related to TStr::peek_tok for the details.
+    pub fn peek_tok(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<Token<'static>, Error> {
+        self.with_tstr(|tstr| tstr.peek_tok(dlimdef, btrim).map(Token::into_owned))
+    }
+
+    /// NOTE: Look at the doc related to TStr::tokens_vec for the details.
+    pub fn tokens_vec(&mut self, dlimdef: impl DelimDef, btrim: bool, bcontinue_onerr: bool) -> Result<Vec<String>, Error> {
+        self.with_tstr(|tstr| {
+            let vtoks = tstr.tokens_vec(dlimdef, btrim, bcontinue_onerr)?;
+            Ok(vtoks.into_iter().map(Cow::into_owned).collect())
+        })
+    }
+
+    /// NOTE: Look at the doc related to TStr::tokens_vec_ex for the details.
+    pub fn tokens_vec_ex(&mut self, dlimdef: impl DelimDef, btrim: bool, bcontinue_onerr: bool) -> Result<Vec<Token<'static>>, Error> {
+        self.with_tstr(|tstr| {
+            let vtoks = tstr.tokens_vec_ex(dlimdef, btrim, bcontinue_onerr)?;
+            Ok(vtoks.into_iter().map(Token::into_owned).collect())
+        })
+    }
+
+    /// NOTE: Look at the doc related to TStr::tokens_vec_recover for the details.
+    pub fn tokens_vec_recover(&mut self, dlimdef: impl DelimDef, btrim: bool, recovery: Recovery) -> (Vec<Token<'static>>, Vec<Error>) {
+        self.with_tstr(|tstr| {
+            let (vtoks, verrs) = tstr.tokens_vec_recover(dlimdef, btrim, recovery);
+            (vtoks.into_iter().map(Token::into_owned).collect(), verrs)
+        })
+    }
+
+    /// NOTE: Look at the doc related to TStr::split_once for the details.
+    pub fn split_once(&mut self, dlimdef: impl DelimDef) -> Result<(String, String), Error> {
+        self.with_tstr(|tstr| {
+            let (tok, rest) = tstr.split_once(dlimdef)?;
+            Ok((tok.into_owned(), rest.to_string()))
+        })
+    }
+
+    /// NOTE: Look at the doc related to TStr::splitn for the details.
+    pub fn splitn(&mut self, reqcnt: usize, dlimdef: impl DelimDef) -> Result<Vec<String>, Error> {
+        self.with_tstr(|tstr| {
+            let vtoks = tstr.splitn(reqcnt, dlimdef)?;
+            Ok(vtoks.into_iter().map(Cow::into_owned).collect())
+        })
+    }
+
+    /// NOTE: Look at the doc related to TStr::splitn_ex for the details.
+    pub fn splitn_ex(&mut self, reqcnt: usize, dlimdef: impl DelimDef) -> Result<Vec<Token<'static>>, Error> {
+        self.with_tstr(|tstr| {
+            let vtoks = tstr.splitn_ex(reqcnt, dlimdef)?;
+            Ok(vtoks.into_iter().map(Token::into_owned).collect())
+        })
+    }
+
+    /// NOTE: Look at the doc related to TStr::nexttok_as for the details.
+    pub fn nexttok_as<T: FromStr>(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<T, Error> {
+        self.with_tstr(|tstr| tstr.nexttok_as(dlimdef, btrim))
+    }
+
+    /// NOTE: Look at the doc related to TStr::nexttok_int for the details.
+    pub fn nexttok_int<T: TryFrom<i128> + TryFrom<u128>>(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<T, Error> {
+        self.with_tstr(|tstr| tstr.nexttok_int(dlimdef, btrim))
+    }
+
+    /// NOTE: Look at the doc related to TStr::nexttok_float for the details.
+    pub fn nexttok_float(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<f64, Error> {
+        self.with_tstr(|tstr| tstr.nexttok_float(dlimdef, btrim))
+    }
+
+    /// NOTE: Look at the doc related to TStr::nexttok_bool for the details.
+    pub fn nexttok_bool(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<bool, Error> {
+        self.with_tstr(|tstr| tstr.nexttok_bool(dlimdef, btrim))
+    }
+
+    /// NOTE: Look at the doc related to TStr::tokens for the details.
+    pub fn tokens<D: DelimDef>(&mut self, dlimdef: D, btrim: bool) -> TStringTokens<'_, D> {
+        TStringTokens {
+            tstring: self,
+            dlimdef,
+            btrim,
+        }
+    }
+
+    /// NOTE: Look at the doc related to TStr::parse_tree for the details.
+    pub fn parse_tree(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<Vec<TokenTree<'static>>, Error> {
+        self.with_tstr(|tstr| {
+            let vtree = tstr.parse_tree(dlimdef, btrim)?;
+            Ok(vtree.into_iter().map(TokenTree::into_owned).collect())
+        })
+    }
+
+    /// NOTE: Look at the doc related to TStr::peel_bracket for the details.
+    pub fn peel_bracket(&mut self, bracket_begin: Option<&str>) -> Result<BracketBlock<'static>, Error> {
+        self.with_tstr(|tstr| tstr.peel_bracket(bracket_begin).map(BracketBlock::into_owned))
+    }
+
+    /// NOTE: Look at the doc related to TStr::peel_string for the details.
+    pub fn peel_string(&mut self, stringdelim: Option<&str>) -> Result<String, Error> {
+        self.with_tstr(|tstr| tstr.peel_string(stringdelim).map(Cow::into_owned))
+    }
+
+}
+
+/// The iterator over the tokens in a TString, returned by TString::tokens.
+pub struct TStringTokens<'t, D: DelimDef> {
+    tstring: &'t mut TString,
+    dlimdef: D,
+    btrim: bool,
+}
+
+impl<'t, D: DelimDef> Iterator for TStringTokens<'t, D> {
+    type Item = Result<Token<'static>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (dlimdef, btrim) = (&self.dlimdef, self.btrim);
+        self.tstring.with_tstr(|tstr| tstr.tokens(dlimdef, btrim).next().map(|gotr| gotr.map(Token::into_owned)))
+    }
+}
+
+/// Once the TString is fully consumed, it remains so.
+impl<'t, D: DelimDef> FusedIterator for TStringTokens<'t, D> {}
+
+
+/// Create a TString, which has its own copy of the original string, and is at
+/// the same position with the same characteristics, as the given TStr.
+impl<'a> From<&TStr<'a>> for TString {
+    fn from(tstr: &TStr<'a>) -> TString {
+        let start = tstr.orig_offset();
+        TString {
+            theOrig: Arc::from(tstr.theOrig),
+            start,
+            end: start + tstr.theStr.len(),
+            trimmedPrefixCnt: tstr.trimmedPrefixCnt,
+            trimmedSuffixCnt: tstr.trimmedSuffixCnt,
+            cfg: tstr.cfg.clone(),
+        }
+    }
+}
+
+impl<'a> From<TStr<'a>> for TString {
+    fn from(tstr: TStr<'a>) -> TString {
+        TString::from(&tstr)
+    }
+}
+
+/// Add support for std::fmt::Display trait
+impl fmt::Display for TString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.the_str())
+    }
+}
@@ -0,0 +1,76 @@
+//!
+//! Typed - Extract tokens as values of the required type
+//! HanishKVC, 2022
+//!
+
+use std::str::FromStr;
+
+use crate::{TStr, Token, Error, ErrorKind, DelimDef};
+use crate::util;
+
+
+/// Typed token extraction related methods
+impl<'a> TStr<'a> {
+
+    /// Create a error of the given kind, wrt the given token, which couldnt be converted
+    fn error_value(&self, kind: ErrorKind, token: Token<'a>) -> Error {
+        let ch = token.text.chars().next().unwrap_or(' ');
+        let ipos = self.theOrig[..token.span.start].chars().count();
+        let mut err = Error::new(kind, ch, ipos, token.span.start, token.text.into_owned());
+        err.span = Some(token.span);
+        err
+    }
+
+    /// Extract the next token, and convert it using the given parse logic
+    fn nexttok_parse<T>(&mut self, dlimdef: impl DelimDef, btrim: bool, parse: impl Fn(&str) -> Result<T, ErrorKind>) -> Result<T, Error> {
+        let token = self.nexttok_ex(dlimdef, btrim)?;
+        match parse(&token.text) {
+            Ok(value) => Ok(value),
+            Err(kind) => Err(self.error_value(kind, token)),
+        }
+    }
+
+    ///
+    /// Extract the next token, and convert it to the requested type, using its FromStr.
+    ///
+    /// If the token cant be converted, a InvalidValue error is returned, which contains
+    /// the token and its span. The token is consumed in either case.
+    ///
+    /// NOTE: Look at the doc related to nexttok_ex for more details.
+    ///
+    pub fn nexttok_as<T: FromStr>(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<T, Error> {
+        self.nexttok_parse(dlimdef, btrim, |s| s.parse::<T>().map_err(|_| ErrorKind::InvalidValue))
+    }
+
+    ///
+    /// Extract the next token, as a integer of the requested type (i64, u8, ...).
+    ///
+    /// The token can have a sign, a radix prefix (0x, 0o or 0b) and _ as digit separators.
+    /// A InvalidNumber or NumberOutOfRange error is returned, if the token is not a valid
+    /// integer or doesnt fit in the requested type. The token is consumed in either case.
+    ///
+    pub fn nexttok_int<T: TryFrom<i128> + TryFrom<u128>>(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<T, Error> {
+        self.nexttok_parse(dlimdef, btrim, util::parse_int::<T>)
+    }
+
+    ///
+    /// Extract the next token, as a floating point number.
+    ///
+    /// The token can have _ as digit separators between digits. A InvalidNumber error is
+    /// returned, if the token is not a valid number. The token is consumed in either case.
+    ///
+    pub fn nexttok_float(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<f64, Error> {
+        self.nexttok_parse(dlimdef, btrim, util::parse_float)
+    }
+
+    ///
+    /// Extract the next token, as a boolean, ie true/false, yes/no, on/off or 1/0 in any case.
+    ///
+    /// A InvalidValue error is returned, if the token is not one of these. The token is
+    /// consumed in either case.
+    ///
+    pub fn nexttok_bool(&mut self, dlimdef: impl DelimDef, btrim: bool) -> Result<bool, Error> {
+        self.nexttok_parse(dlimdef, btrim, util::parse_bool)
+    }
+
+}
@@ -3,6 +3,8 @@
 //! HanishKVC, 2022
 //!
 
+use crate::ErrorKind;
+
 
 ///
 /// Remove extra space (ie beyond a single space) outside double quoted text in a line.
@@ -58,3 +60,82 @@ pub fn remove_extra_whitespaces(ins: &str) -> String {
     }
     outs
 }
+
+
+///
+/// Parse a integer literal, which can have
+/// * a sign (+ or -)
+/// * a radix prefix, 0x (hex), 0o (octal) or 0b (binary), in either case
+/// * _ as digit separators, anywhere after the 1st digit (or radix prefix)
+///
+/// Returns InvalidNumber if it is not a valid integer literal, or NumberOutOfRange
+/// if it doesnt fit within the requested integer type (i64, u128, ...).
+///
+/// The digits are parsed as a u128 magnitude, with the sign applied afterwards, so
+/// that the full range of both u128 and i128 is supported.
+///
+pub fn parse_int<T: TryFrom<i128> + TryFrom<u128>>(s: &str) -> Result<T, ErrorKind> {
+    let (bneg, rest) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (radix, digits) = match rest.get(..2) {
+        Some("0x") | Some("0X") => (16, &rest[2..]),
+        Some("0o") | Some("0O") => (8, &rest[2..]),
+        Some("0b") | Some("0B") => (2, &rest[2..]),
+        _ => (10, rest),
+    };
+    if ((radix == 10) && digits.starts_with('_')) || !digits.chars().all(|c| (c == '_') || c.is_digit(radix)) {
+        return Err(ErrorKind::InvalidNumber);
+    }
+    let digits = digits.replace('_', "");
+    if digits.is_empty() {
+        return Err(ErrorKind::InvalidNumber);
+    }
+    let magnitude = u128::from_str_radix(&digits, radix).map_err(|_| ErrorKind::NumberOutOfRange)?;
+    let value = if bneg {
+        match 0i128.checked_sub_unsigned(magnitude) {
+            Some(value) => T::try_from(value).ok(),
+            None => None,
+        }
+    } else {
+        T::try_from(magnitude).ok()
+    };
+    value.ok_or(ErrorKind::NumberOutOfRange)
+}
+
+///
+/// Parse a floating point literal, which can have _ as digit separators between digits.
+///
+/// Returns InvalidNumber if it is not a valid floating point literal.
+///
+pub fn parse_float(s: &str) -> Result<f64, ErrorKind> {
+    let vchars: Vec<char> = s.chars().collect();
+    for (i, c) in vchars.iter().enumerate() {
+        if *c != '_' {
+            continue;
+        }
+        let prev = vchars[..i].iter().rev().find(|c| **c != '_');
+        let next = vchars[i+1..].iter().find(|c| **c != '_');
+        if !prev.is_some_and(|c| c.is_ascii_digit()) || !next.is_some_and(|c| c.is_ascii_digit()) {
+            return Err(ErrorKind::InvalidNumber);
+        }
+    }
+    match s.replace('_', "").parse::<f64>() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(ErrorKind::InvalidNumber),
+    }
+}
+
+///
+/// Parse a boolean, ie true/false, yes/no, on/off or 1/0, in any case.
+///
+/// Returns InvalidValue if it is not one of these.
+///
+pub fn parse_bool(s: &str) -> Result<bool, ErrorKind> {
+    match s.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(ErrorKind::InvalidValue),
+    }
+}